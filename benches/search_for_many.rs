@@ -1,25 +1,66 @@
+use std::io::Cursor;
 use std::path::PathBuf;
 use criterion::{criterion_group, criterion_main, Criterion};
 use puzzle_tools::wordlist::trie::searchconfig::SearchConfig;
 use puzzle_tools::wordlist::wordlist::{FileFormat, Wordlist};
 
+/// How many words to make up when data/allwords2.txt isn't there.
+const GENERATED_WORDS: usize = 100000;
+
+/// `n` made-up words of 3 to 9 letters, alternating consonants and vowels. They're the same on
+/// every run, so the benchmarks can be compared without the real wordlist.
+fn generated_words(n: usize) -> String {
+    let consonants = b"BCDFGHKLMNPRSTVW";
+    let vowels = b"AEIOU";
+    let mut seed: u64 = 0x2545f4914f6cdd1d;
+    let mut next = |m: usize| {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        (seed % m as u64) as usize
+    };
+    let mut words = String::new();
+    for _ in 0..n {
+        let len = 3 + next(7);
+        let vowel_first = next(2) == 0;
+        for i in 0..len {
+            let letter = if (i % 2 == 0) == vowel_first {
+                vowels[next(vowels.len())]
+            } else {
+                consonants[next(consonants.len())]
+            };
+            words.push(letter as char);
+        }
+        words.push('\n');
+    }
+    words
+}
 
 fn criterion_benchmark(c: &mut Criterion) {
     let mut data_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
     data_dir.push("data/allwords2.txt");
     let wl = Wordlist::new();
-    wl.load_file(data_dir.to_str().unwrap(),
-                 FileFormat::builder().build());
+    if data_dir.exists() {
+        wl.load_file(data_dir.to_str().unwrap(),
+                     FileFormat::builder().build());
+    } else {
+        println!("{} not found; using {} generated words", data_dir.display(), GENERATED_WORDS);
+        wl.load_reader(Cursor::new(generated_words(GENERATED_WORDS)), FileFormat::builder().build());
+    }
+
+    if std::thread::available_parallelism().map_or(true, |n| n.get() == 1) {
+        println!("only one CPU available; the multithreaded timings won't show any speed-up");
+    }
 
     let config = SearchConfig::new();
 
-    //c.bench_function("len 3", |b| b.iter(|| wl.search("...")));
+    //c.bench_function("len 3", |b| b.iter(|| wl.search("...", &config)));
 
     {
         let mut group = c.benchmark_group("10s");
         group.sample_size(10);
-        group.bench_function("len 5", |b| b.iter(|| wl.search(".....")));
-        group.bench_function("len 7", |b| b.iter(|| wl.search(".......")));
+        group.bench_function("len 5", |b| b.iter(|| wl.search(".....", &config)));
+        group.bench_function("len 7", |b| b.iter(|| wl.search(".......", &config)));
     }
 
     {
@@ -28,6 +69,18 @@ fn criterion_benchmark(c: &mut Criterion) {
         group.bench_function("len 5 (multithreaded)", |b| b.iter(|| wl.search_multithreaded(".....", &config)));
         group.bench_function("len 7 (multithreaded)", |b| b.iter(|| wl.search_multithreaded(".......", &config)));
     }
+
+    // short queries, where the cost of handing candidates between threads matters most
+    {
+        let mut phrases = config.clone();
+        phrases.spaces_allowed = 1;
+        phrases.space_penalty = Some(5000);
+        let mut group = c.benchmark_group("short");
+        group.bench_function("len 3 (multithreaded)", |b| b.iter(|| wl.search_multithreaded("...", &config)));
+        group.bench_function("anagram 5 (multithreaded)", |b| b.iter(|| wl.anagram_multithreaded("TRAPS", &config)));
+        group.bench_function("anagram 6 with a space (multithreaded)",
+                             |b| b.iter(|| wl.anagram_multithreaded("TRAPSE", &phrases)));
+    }
 }
 
 criterion_group!(benches, criterion_benchmark);
@@ -1,3 +1,7 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use unicode_normalization::char::{decompose_canonical, is_combining_mark};
 
 pub const ALPHABET : &[u8] = "ABCDEFGHIJKLMNOPQRSTUVWXYZ ".as_bytes();
 pub fn get_idx(a: char) -> usize {
@@ -6,6 +10,143 @@ pub fn get_idx(a: char) -> usize {
     }
     (a.to_ascii_uppercase() as u8 - 'A' as u8) as usize
 }
+
+static DEFAULT_FOLDING: OnceLock<Folding> = OnceLock::new();
+
+/// Normalizes `s` with the default folding.
 pub fn normalize(s: &str) -> String {
-    s.to_ascii_uppercase().chars().filter(|&x| ALPHABET.contains(&(x as u8))).collect()
+    DEFAULT_FOLDING.get_or_init(Folding::default).normalize(s)
+}
+
+/// Maps accented and other non-ASCII letters onto the index alphabet.
+///
+/// Characters with an explicit mapping in the table are replaced by it. Everything else is
+/// uppercased, canonically decomposed and stripped of combining marks, so "CAFÉ" becomes "CAFE".
+/// The default table covers letters that don't decompose (ß→SS, Æ→AE, Ø→O, ...); puzzles that
+/// treat a letter like Ñ as its own letter can map it to a distinct spelling instead.
+#[derive(Clone, Debug)]
+pub struct Folding {
+    table: HashMap<char, String>,
+}
+
+impl Default for Folding {
+    fn default() -> Self {
+        let table = [
+            ('ß', "SS"), ('ẞ', "SS"),
+            ('Æ', "AE"), ('Œ', "OE"),
+            ('Ø', "O"), ('Ł', "L"),
+            ('Đ', "D"), ('Ð', "D"),
+            ('Þ', "TH"), ('Ħ', "H"),
+            ('Ŀ', "L"), ('Ŧ', "T"),
+            ('Ŋ', "NG"), ('Ĳ', "IJ"),
+        ];
+        Folding {
+            table: table.iter().map(|(c, s)| (*c, s.to_string())).collect()
+        }
+    }
+}
+
+impl Folding {
+    /// A folding with no explicit mappings; only decomposition is applied.
+    pub fn empty() -> Folding {
+        Folding { table: HashMap::new() }
+    }
+
+    /// Maps `from` (and its uppercase, if that's a single letter) to `to`.
+    pub fn with_mapping(mut self, from: char, to: &str) -> Folding {
+        for c in Self::keys(from) {
+            self.table.insert(c, to.to_uppercase());
+        }
+        self
+    }
+
+    pub fn without_mapping(mut self, from: char) -> Folding {
+        for c in Self::keys(from) {
+            self.table.remove(&c);
+        }
+        self
+    }
+
+    /// The table entries for `c`: itself and its uppercase, unless that's several letters (like
+    /// ß's SS), which would be the entries of other letters.
+    fn keys(c: char) -> Vec<char> {
+        let mut upper = c.to_uppercase();
+        match (upper.next(), upper.next()) {
+            (Some(u), None) if u != c => vec![c, u],
+            _ => vec![c],
+        }
+    }
+
+    /// Uppercases and folds `s`, leaving ASCII punctuation alone so it can be used on queries.
+    pub fn fold(&self, s: &str) -> String {
+        let mut folded = String::with_capacity(s.len());
+        for c in s.chars() {
+            // looked up before uppercasing too, since that can turn a letter into others
+            if let Some(mapped) = self.table.get(&c) {
+                folded.push_str(mapped);
+                continue;
+            }
+            for c in c.to_uppercase() {
+                if let Some(mapped) = self.table.get(&c) {
+                    folded.push_str(mapped);
+                } else if c.is_ascii() {
+                    folded.push(c);
+                } else {
+                    decompose_canonical(c, |d| {
+                        if !is_combining_mark(d) {
+                            folded.push(d);
+                        }
+                    });
+                }
+            }
+        }
+        folded
+    }
+
+    /// Folds `s` and drops everything that isn't in the alphabet.
+    pub fn normalize(&self, s: &str) -> String {
+        self.fold(s).chars().filter(|&x| x.is_ascii() && ALPHABET.contains(&(x as u8))).collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::alphabet::{Folding, normalize};
+
+    #[test]
+    fn strips_diacritics() {
+        assert_eq!(normalize("café"), "CAFE");
+        assert_eq!(normalize("CAFÉ"), "CAFE");
+        assert_eq!(normalize("Ångström"), "ANGSTROM");
+        assert_eq!(normalize("AÑO"), "ANO");
+    }
+
+    #[test]
+    fn expands_ligatures() {
+        assert_eq!(normalize("Straße"), "STRASSE");
+        assert_eq!(normalize("Æsir"), "AESIR");
+        assert_eq!(normalize("œuvre"), "OEUVRE");
+    }
+
+    #[test]
+    fn custom_mapping() {
+        let folding = Folding::default().with_mapping('ñ', "NY");
+        assert_eq!(folding.normalize("año"), "ANYO");
+        assert_eq!(Folding::default().without_mapping('æ').normalize("Æsir"), "SIR");
+    }
+
+    #[test]
+    fn sharp_s_has_its_own_mapping() {
+        assert_eq!(Folding::default().with_mapping('ß', "sz").normalize("Straße"), "STRASZE");
+        assert_eq!(Folding::default().with_mapping('ß', "sz").normalize("STRAẞE"), "STRASSE");
+        // unmapped, ß still uppercases to SS, without touching the mapping for S
+        let folding = Folding::empty().with_mapping('s', "Z").without_mapping('ß');
+        assert_eq!(folding.normalize("sß"), "ZZZ");
+    }
+
+    #[test]
+    fn fold_keeps_regex_syntax() {
+        assert_eq!(Folding::default().fold("caf(é|e).*"), "CAF(E|E).*");
+    }
 }
@@ -1,4 +1,4 @@
 pub mod wordlist;
 pub mod ciphers;
-mod alphabet;
+pub mod alphabet;
 mod regex;
\ No newline at end of file
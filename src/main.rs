@@ -4,13 +4,22 @@ mod alphabet;
 mod regex;
 
 
+use std::fs::OpenOptions;
 use std::io::{self, Write};
+use std::path::PathBuf;
 use std::io::stdin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use indicatif::{ProgressBar, ProgressStyle};
 use serde_json::from_str;
 use structopt::StructOpt;
-use crate::wordlist::trie::searchconfig::SearchConfig;
+use crate::wordlist::paged_search::PagedSearch;
+use crate::wordlist::stats;
+use crate::wordlist::trie::scorer;
+use crate::wordlist::trie::searchconfig::{CancellationToken, FrontierLimit, PageLimits, SearchConfig};
+use crate::wordlist::trie::searchresult::SearchResult;
 
-use crate::wordlist::wordlist::{FileFormat, Wordlist};
+use crate::wordlist::wordlist::{FileFormat, LoadProgress, Wordlist, WordlistEvent};
 
 
 /// Search for a pattern in a file and display the lines that contain it.
@@ -23,6 +32,55 @@ struct Cli {
     #[structopt(long)]
     web: bool,
 
+    /// Read the wordlist from stdin instead of a file
+    #[structopt(long)]
+    stdin: bool,
+
+    /// Column separator of the wordlist; without one, each line is a word with frequency 1
+    #[structopt(long)]
+    delimiter: Option<char>,
+
+    /// Column holding the word (with --delimiter, default 0)
+    #[structopt(long)]
+    word_column: Option<usize>,
+
+    /// Column holding the word's frequency (with --delimiter, default 1)
+    #[structopt(long)]
+    freq_column: Option<usize>,
+
+    /// Least frequent word allowed in results (default 100, or 0 if the wordlist has no
+    /// frequencies)
+    #[structopt(long)]
+    prune_freq: Option<usize>,
+
+    /// Exclusion lists; words in them never show up in results
+    #[structopt(long, parse(from_os_str))]
+    exclude: Vec<PathBuf>,
+
+    /// Category files given as TAG=PATH; every word in PATH gets tagged with TAG
+    #[structopt(long)]
+    tag_file: Vec<String>,
+
+    /// Word pair counts (lines of FIRST SECOND COUNT) used to rank multi-word results
+    #[structopt(long, parse(from_os_str))]
+    bigrams: Option<PathBuf>,
+
+    /// Where words banned from the prompt are saved (and loaded from on startup)
+    #[structopt(long, parse(from_os_str), default_value = "data/banned")]
+    banlist: PathBuf,
+
+    /// Print each result's score and word frequencies
+    #[structopt(long)]
+    scores: bool,
+
+    /// Print results as JSON, one per line
+    #[structopt(long)]
+    json: bool,
+
+    /// Return results in exact score order, the same on every run
+    #[structopt(long)]
+    deterministic: bool,
+
     #[structopt(long)]
     anagram: Option<String>,
 
@@ -33,38 +91,231 @@ struct Cli {
 
 fn run_web() {}
 
+/// How to read the wordlist given on the command line (or piped in).
+fn file_format(args: &Cli) -> FileFormat {
+    match args.delimiter {
+        Some(delimiter) => FileFormat::builder()
+            .delimiter(delimiter)
+            .word_column(args.word_column.unwrap_or(0))
+            .freq_column(args.freq_column.unwrap_or(1))
+            .build(),
+        None => FileFormat::builder().build(),
+    }
+}
+
+/// Prints the next page of `search`. Ctrl-C (or `time_limit` running out) cuts the page short,
+/// but `more` can still pick the search up again.
+fn run_page(current_search: &Mutex<Option<CancellationToken>>, search: &mut PagedSearch,
+            page_size: usize, time_limit: Option<Duration>, args: &Cli) {
+    let token = CancellationToken::new();
+    let deadline = time_limit.map(|limit| Instant::now() + limit);
+    let limits = PageLimits {
+        cancel: Some(token.clone()),
+        deadline,
+    };
+    *current_search.lock().unwrap() = Some(token.clone());
+    let page = search.next_page_with(page_size, limits);
+    *current_search.lock().unwrap() = None;
+    page.iter().for_each(|result| print_result(result, args));
+    let discarded = search.summary().discarded;
+    if discarded > 0 {
+        println!("{} candidates discarded to stay within the frontier limit; results may be missing", discarded);
+    }
+    let timed_out = deadline.is_some_and(|deadline| Instant::now() >= deadline);
+    if token.is_cancelled() {
+        println!("Search cancelled");
+    } else if timed_out && page.len() < page_size {
+        println!("Time limit reached, type `more` to continue");
+    } else if page.is_empty() {
+        println!("No more results");
+    }
+}
+
+fn print_result(result: &SearchResult, args: &Cli) {
+    if args.json {
+        println!("{}", serde_json::to_string(result).unwrap());
+    } else if args.scores && result.discarded > 0 {
+        println!("{} (score {}, freqs {:?}, {} candidates discarded)",
+                 result, result.score, result.word_freqs, result.discarded);
+    } else if args.scores {
+        println!("{} (score {}, freqs {:?})", result, result.score, result.word_freqs);
+    } else {
+        println!("{}", result);
+    }
+}
+
+/// Shows what the wordlist is doing on stderr, with a progress bar while it loads, so that stdout
+/// only has results on it.
+fn show_progress() -> impl Fn(&WordlistEvent) + Send + Sync {
+    let bar: Mutex<Option<ProgressBar>> = Mutex::new(None);
+    move |event| {
+        let mut bar = bar.lock().unwrap();
+        match event {
+            WordlistEvent::ReadingWords { path } => eprintln!("Reading words from {:?}", path),
+            WordlistEvent::ReadingExclusions { path } => eprintln!("Reading exclusions from {:?}", path),
+            WordlistEvent::ReadingTags { path, tag } => eprintln!("Reading {} tags from {:?}", tag, path),
+            WordlistEvent::ReadingBigrams { path } => eprintln!("Reading word pairs from {:?}", path),
+            WordlistEvent::BigramsLoaded { pairs, failures } =>
+                eprintln!("Read {} word pairs [{} failures]", pairs, failures),
+            WordlistEvent::ListLoaded { words, failures } =>
+                eprintln!("Read {} words [{} failures]", words, failures),
+            // parsing starts while lines are still being read, so the bar only gets a length
+            // once they all have been
+            WordlistEvent::Load(LoadProgress::Read { lines }) => {
+                let bar = bar.get_or_insert_with(parsing_bar);
+                bar.set_length(*lines as u64);
+                bar.set_style(ProgressStyle::default_bar()
+                    .template("Parsing [{bar:40}] {pos}/{len} lines ({eta})").unwrap());
+            }
+            WordlistEvent::Load(LoadProgress::Parsed { lines, .. }) =>
+                bar.get_or_insert_with(parsing_bar).set_position(*lines as u64),
+            WordlistEvent::Load(LoadProgress::Inserted { .. }) => {}
+            WordlistEvent::Load(LoadProgress::Done { words, failures, elapsed }) => {
+                if let Some(bar) = bar.take() {
+                    bar.finish_and_clear();
+                }
+                eprintln!("Read {} words in {:.3}s [{} failures ({:.2}%)]",
+                          words, elapsed.as_secs_f64(), failures,
+                          100.0 * (*failures as f64) / (*words.max(&1) as f64));
+            }
+            WordlistEvent::Building => eprintln!("Building index..."),
+            WordlistEvent::Built { elapsed, memory } =>
+                eprintln!("Built index in {:.3}s ({})", elapsed.as_secs_f64(), memory),
+        }
+    }
+}
+
+/// A progress bar for parsing lines before it's known how many there are.
+fn parsing_bar() -> ProgressBar {
+    let bar = ProgressBar::no_length();
+    bar.set_style(ProgressStyle::default_spinner().template("Parsing {pos} lines").unwrap());
+    bar
+}
+
 fn main() {
     let args = Cli::from_args();
 
-    let wl = Wordlist::new();
-    if args.path.is_some() {
-        wl.load_file(args.path.unwrap().as_path().to_str().unwrap(),
-                     FileFormat::builder().build());
+    let mut wl = Wordlist::new();
+    wl.set_event_handler(show_progress());
+    // these rebuild the index, which is quick while it's still empty; the words then merge in
+    for exclusions in &args.exclude {
+        if let Err(e) = wl.load_exclusions(exclusions.to_str().unwrap()) {
+            eprintln!("Couldn't load {}: {}", exclusions.display(), e);
+        }
+    }
+    for tag_file in &args.tag_file {
+        match tag_file.split_once('=') {
+            Some((tag, path)) => if let Err(e) = wl.load_tag_file(path, tag) {
+                eprintln!("Couldn't load {}: {}", path, e);
+            },
+            None => eprintln!("Ignoring --tag-file {} (should be TAG=PATH)", tag_file)
+        }
+    }
+    if args.banlist.exists() {
+        if let Err(e) = wl.load_exclusions(args.banlist.to_str().unwrap()) {
+            eprintln!("Couldn't load {}: {}", args.banlist.display(), e);
+        }
+    }
+    let has_freqs;
+    if args.stdin {
+        let format = file_format(&args);
+        has_freqs = format.has_freqs();
+        wl.set_name("stdin");
+        wl.load_reader(io::stdin().lock(), format);
+    } else if let Some(path) = args.path.as_ref() {
+        let format = file_format(&args);
+        has_freqs = format.has_freqs();
+        wl.set_name(path.to_str().unwrap());
+        wl.load_file(path.to_str().unwrap(), format);
     } else {
+        has_freqs = true;
+        wl.set_name("data/with_freqs");
         //wl.load_file("/usr/share/dict/words",
         //             FileFormat::builder().build());
         wl.load_file("data/with_freqs",
                      FileFormat::builder().delimiter(' ').word_column(1).freq_column(0).build())
     }
+    if let Some(bigrams) = args.bigrams.as_ref() {
+        if let Err(e) = wl.load_bigrams(bigrams.to_str().unwrap()) {
+            eprintln!("Couldn't load {}: {}", bigrams.display(), e);
+        }
+    }
     let mut default_config = SearchConfig::new();
     default_config.space_penalty = Some(5000);//Some(6187267);
     default_config.spaces_allowed = 2;
     default_config.max_results = Some(50);
-    default_config.prune_freq = 100;
+    // every word in a list without frequencies has frequency 1, so don't prune them all
+    default_config.prune_freq = args.prune_freq.unwrap_or(if has_freqs { 100 } else { 0 });
+    default_config.deterministic = args.deterministic;
+
+    if args.anagram.is_some() || args.regex.is_some() {
+        let results = match (&args.anagram, &args.regex) {
+            (Some(anagram), _) => wl.anagram_iter(anagram, &default_config),
+            (None, Some(regex)) => wl.search_iter(regex, &default_config),
+            (None, None) => unreachable!(),
+        };
+        results.for_each(|result| print_result(&result, &args));
+        return;
+    }
+
+    // Ctrl-C stops the running search, or quits if there isn't one
+    let current_search = Arc::new(Mutex::new(None::<CancellationToken>));
+    {
+        let current_search = current_search.clone();
+        ctrlc::set_handler(move || match current_search.lock().unwrap().as_ref() {
+            Some(token) => token.cancel(),
+            None => std::process::exit(130),
+        }).unwrap();
+    }
+    let stats = stats::install();
+    let mut time_limit = None;
+    // the last anagram or regex search, for `more` to continue
+    let mut last_search: Option<PagedSearch> = None;
 
     loop {
         let mut command = String::new();
         print!("> ");
         io::stdout().flush().unwrap();
-        stdin().read_line(&mut command);
+        if stdin().read_line(&mut command).unwrap_or(0) == 0 {
+            // stdin is closed (or was used for the wordlist), nothing more to read
+            return;
+        }
 
-        if command == "exit" { return; }
+        if command.trim() == "exit" { return; }
+        if command.trim() == "memory" {
+            println!("{}", wl.memory_usage());
+            continue;
+        }
+        if command.trim() == "stats" {
+            print!("{}", stats);
+            continue;
+        }
+        if command.trim() == "stats reset" {
+            stats.reset();
+            continue;
+        }
+        if command.trim() == "more" {
+            match last_search.as_mut() {
+                Some(search) => run_page(&current_search, search, default_config.max_results.unwrap_or(50),
+                                         time_limit, &args),
+                None => println!("Nothing to continue; search for something first"),
+            }
+            continue;
+        }
         let mut split = command.splitn(2, " ");
         let command = split.next();
         let arg = split.next();
         if command.is_none() || arg.is_none() {
-            println!("anagram SOMETHING, regex SOMETHING use all caps\n\
-            or set [max_results,spaces_allowed,prune_freq,space_penalty] <number> (ask pranjal what these mean if unclear)");
+            println!("anagram SOMETHING, regex SOMETHING use all caps (_ in a regex marks where words break)\n\
+            or set [max_results,spaces_allowed,prune_freq,space_penalty,deterministic,time_limit,max_expansions,min_length,max_length,min_word_len,max_word_len,max_frontier,beam_width] <number> (ask pranjal what these mean if unclear)\n\
+            or add WORD [freq], remove WORD to change the wordlist\n\
+            or ban WORD to hide it from results for good\n\
+            or tags TAG,TAG... to only show words with those tags (tags none to show everything)\n\
+            or scorer [default,weighted,freq,logprob,normalized,geomean] to change how results are ranked\n\
+            or more to get the next page of results from the last search\n\
+            or explain WORD regex|anagram SOMETHING to see why WORD does or doesn't turn up (TOO_MANY for a phrase)\n\
+            or stats to see what searches have been doing (stats reset to start counting again)\n\
+            or memory to see how big the index is");
             continue;
         }
 
@@ -91,29 +342,90 @@ fn main() {
                 "spaces_allowed" => default_config.spaces_allowed = val,
                 "prune_freq" => default_config.prune_freq = val,
                 "space_penalty" => default_config.space_penalty = Some(val),
+                "deterministic" => default_config.deterministic = val != 0,
+                "time_limit" => time_limit = Some(Duration::from_secs(val as u64)).filter(|x| !x.is_zero()),
+                "max_expansions" => default_config.max_expansions = Some(val).filter(|x| *x > 0),
+                "min_length" => default_config.min_length = Some(val).filter(|x| *x > 0),
+                "max_length" => default_config.max_length = Some(val).filter(|x| *x > 0),
+                "min_word_len" => default_config.min_word_len = val,
+                "max_word_len" => default_config.max_word_len = Some(val).filter(|x| *x > 0),
+                "max_frontier" => default_config.frontier_limit = Some(FrontierLimit::Total(val)).filter(|_| val > 0),
+                "beam_width" => default_config.frontier_limit = Some(FrontierLimit::PerDepth(val)).filter(|_| val > 0),
                 _ => println!("Invalid property")
             }
 
         }
+        // the last search holds on to the index, which would then have to be copied to change it
+        if ["add", "remove", "ban"].contains(&command.unwrap()) {
+            last_search = None;
+        }
+        if command.unwrap() == "add" {
+            let mut split = arg.splitn(2, " ");
+            let word = split.next().unwrap();
+            // default to just above prune_freq so the new word actually shows up
+            let freq = match split.next().map(from_str::<usize>) {
+                None => default_config.prune_freq + 1,
+                Some(Ok(freq)) => freq,
+                Some(Err(_)) => {
+                    println!("Couldn't parse number");
+                    continue;
+                }
+            };
+            wl.add_word(word, freq);
+        }
+        if command.unwrap() == "remove" && !wl.remove_word(arg) {
+            println!("{} isn't in the wordlist", arg);
+        }
+        if command.unwrap() == "tags" {
+            if arg == "none" {
+                default_config.tags = None;
+            } else {
+                match wl.tag_filter(&arg.split(',').collect::<Vec<_>>()) {
+                    Ok(filter) => default_config.tags = Some(filter),
+                    Err(e) => println!("{} (known tags: {})", e, wl.tags().join(", "))
+                }
+            }
+        }
+        if command.unwrap() == "scorer" {
+            match scorer::by_name(arg) {
+                _ if arg == "default" => default_config.scorer = None,
+                Some(scorer) => default_config.scorer = Some(scorer),
+                None => println!("Unknown scorer (should be one of {})", scorer::SCORER_NAMES.join(", ")),
+            }
+        }
+        if command.unwrap() == "ban" {
+            if !wl.ban_word(arg) {
+                println!("{} isn't in the wordlist", arg);
+                continue;
+            }
+            // saved the way the word was indexed
+            let saved = OpenOptions::new().create(true).append(true).open(&args.banlist)
+                .and_then(|mut file| writeln!(file, "{}", wl.normalize(arg)));
+            if let Err(e) = saved {
+                println!("Couldn't save ban to {:?}: {}", args.banlist, e);
+            }
+        }
+        if command.unwrap() == "explain" {
+            let split = arg.splitn(3, " ").collect::<Vec<_>>();
+            let explanation = match split[..] {
+                [word, "regex", query] => wl.explain(&word.replace('_', " "), &wl.regex_constraint(query), &default_config),
+                [word, "anagram", query] => wl.explain(&word.replace('_', " "), &wl.anagram_constraint(query), &default_config),
+                _ => {
+                    println!("Invalid command (should be explain <word> regex|anagram <query>)");
+                    continue;
+                }
+            };
+            println!("{}", explanation);
+        }
         if command.unwrap() == "anagram" {
             println!("Anagramming \"{}\"", arg);
-            let mut counter = 0;
-            wl.anagram_callback(arg,
-                                &default_config, |word, config: &SearchConfig| {
-                    counter += 1;
-                    println!("{}", word);
-                    return counter >= config.max_results.unwrap();
-                });
+            let search = last_search.insert(wl.anagram_paged(arg, &default_config));
+            run_page(&current_search, search, default_config.max_results.unwrap_or(50), time_limit, &args);
         }
 
         if command.unwrap() == "regex" {
-            let mut counter = 0;
-            wl.search_callback(arg,
-                               &default_config, |word, config: &SearchConfig| {
-                    counter += 1;
-                    println!("{}", word);
-                    return counter >= config.max_results.unwrap();
-                });
+            let search = last_search.insert(wl.search_paged(arg, &default_config));
+            run_page(&current_search, search, default_config.max_results.unwrap_or(50), time_limit, &args);
         }
     }
 }
@@ -9,6 +9,7 @@ use std::sync::{Arc, RwLock, RwLockWriteGuard};
 use maplit::hashset;
 use crate::regex::nfa::state::NfaStateKind::Dummy;
 use crate::regex::nfa::state::NfaStatePtr::{Strong, Weak};
+use crate::regex::parse::SEPARATOR;
 
 
 #[derive(Debug)]
@@ -312,7 +313,8 @@ impl NfaState {
         match &self.kind {
             Literal(c) => *c == next_char,
             Set(set) => set.contains(&next_char),
-            Wildcard => true,
+            // a wildcard is a letter, not a word break
+            Wildcard => next_char != SEPARATOR,
             Start => true,
             Accept => true,
             Dummy => true
@@ -400,7 +402,7 @@ mod tests {
         state5.add_successor(&state6);
 
         assert_eq!(state1.clone().non_dummy_successors().iter().collect::<HashSet<_>>(),
-                   vec![state3.clone(), state4.clone()].iter().collect::<HashSet<_>>());
+                   [state3.clone(), state4.clone()].iter().collect::<HashSet<_>>());
         assert_eq!(state3.clone().non_dummy_successors(), vec![state6.clone()]);
         assert_eq!(state3.clone().non_dummy_successors(), vec![state6.clone()]);
     }
@@ -425,6 +427,6 @@ mod tests {
         assert_eq!(state1.clone().non_dummy_successors(), vec![state3.clone()]);
         assert_eq!(state3.clone().non_dummy_successors(), vec![state4.clone()]);
         assert_eq!(state4.clone().non_dummy_successors().iter().collect::<HashSet<_>>(),
-                   vec![state3.clone(), state6.clone()].iter().collect::<HashSet<_>>());
+                   [state3.clone(), state6.clone()].iter().collect::<HashSet<_>>());
     }
 }
\ No newline at end of file
@@ -12,8 +12,12 @@ pub struct Pattern {
     pub(crate) elements: Vec<Element>,
 }
 
+/// A word break in a phrase; `_` or a space in a pattern.
+pub const SEPARATOR: char = ' ';
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Element {
+    /// A letter, or `SEPARATOR`
     Literal(char),
     Wildcard,
     Set(Vec<char>),
@@ -30,6 +34,26 @@ pub fn pattern(input: &str) -> IResult<&str, Pattern> {
     )(input)
 }
 
+impl Pattern {
+    /// Whether the pattern says anything about where words break.
+    pub fn has_separators(&self) -> bool {
+        self.elements.iter().any(Element::has_separators)
+    }
+}
+
+impl Element {
+    fn has_separators(&self) -> bool {
+        use Element::*;
+        match self {
+            Literal(c) => *c == SEPARATOR,
+            Set(chars) => chars.contains(&SEPARATOR),
+            Repeat(element) | Optional(element) => element.has_separators(),
+            Group(patterns) => patterns.iter().any(Pattern::has_separators),
+            Wildcard => false,
+        }
+    }
+}
+
 fn escaped(input: &str) -> IResult<&str, char> {
     preceded(tag("\\"), anychar)(input)
 }
@@ -44,10 +68,15 @@ fn get_one_alphanum(input: &str) -> IResult<&str, char> {
     }
 }
 
+fn separator(input: &str) -> IResult<&str, char> {
+    value(SEPARATOR, alt((tag("_"), tag(" "))))(input)
+}
+
 fn literal(input: &str) -> IResult<&str, char> {
     alt((
         escaped,
-        get_one_alphanum
+        get_one_alphanum,
+        separator,
     ))(input)
 }
 
@@ -97,7 +126,7 @@ fn element(input: &str) -> IResult<&str, Element> {
 
 #[cfg(test)]
 mod tests {
-    use crate::regex::parse::{pattern, Pattern};
+    use crate::regex::parse::{pattern, Pattern, SEPARATOR};
     use crate::regex::parse::Element::*;
 
     #[test]
@@ -161,4 +190,16 @@ mod tests {
             ]
         })
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn parse_with_separators() {
+        let parsed = pattern("ab_c d_?").unwrap().1;
+        assert_eq!(parsed, Pattern {
+            elements: vec![Literal('a'), Literal('b'), Literal(SEPARATOR), Literal('c'), Literal(SEPARATOR),
+                           Literal('d'), Optional(Box::new(Literal(SEPARATOR)))],
+        });
+        assert!(parsed.has_separators());
+        assert!(pattern("a(b|c_)").unwrap().1.has_separators());
+        assert!(!pattern("a(b|c)*.").unwrap().1.has_separators());
+    }
+}
@@ -1,4 +1,7 @@
 mod index;
 pub mod wordlist;
 pub mod trie;
-
+pub mod search_iter;
+pub mod paged_search;
+pub mod bigrams;
+pub mod stats;
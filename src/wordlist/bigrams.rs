@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::sync::Arc;
+
+use crate::alphabet::Folding;
+use crate::wordlist::wordlist::readable_lines;
+use crate::wordlist::trie::scorer::{Candidate, Scorer, LOG_SCALE};
+use crate::wordlist::trie::searchconfig::SearchConfig;
+
+/// How much weight a word's own frequency gets next to the counts of what follows the word
+/// before it; the more often the earlier word has been seen, the more the counts win.
+const SMOOTHING: f64 = 100.0;
+
+/// How much less likely a word is to follow another when no counts back the pair up, so that
+/// strings of common words don't beat real phrases.
+const BACKOFF: f64 = 0.05;
+
+/// Counts of which words follow which, read from lines of `FIRST SECOND COUNT`.
+#[derive(Default, Debug)]
+pub struct BigramModel {
+    following: HashMap<String, Following>,
+}
+
+#[derive(Default, Debug)]
+struct Following {
+    total: usize,
+    /// The highest count of any word after this one
+    best: usize,
+    counts: HashMap<String, usize>,
+}
+
+impl BigramModel {
+    pub fn new() -> BigramModel {
+        Default::default()
+    }
+
+    /// Reads a count file, folding its words with `folding` so they match the wordlist's. Returns
+    /// the model and how many lines couldn't be read.
+    pub fn read<R: BufRead>(reader: R, folding: &Folding) -> (BigramModel, usize) {
+        let mut model = BigramModel::new();
+        let mut failures = 0;
+        let mut unreadable = 0;
+        for line in readable_lines(reader, &mut unreadable) {
+            let columns = line.split_whitespace().collect::<Vec<_>>();
+            match columns[..] {
+                [first, second, count] => match count.parse() {
+                    Ok(count) => model.add(&folding.normalize(first), &folding.normalize(second), count),
+                    Err(_) => failures += 1,
+                },
+                [] => {}
+                _ => failures += 1,
+            }
+        }
+        (model, failures + unreadable)
+    }
+
+    pub fn add(&mut self, first: &str, second: &str, count: usize) {
+        let following = self.following.entry(first.to_string()).or_default();
+        following.total += count;
+        let pair = following.counts.entry(second.to_string()).or_insert(0);
+        *pair += count;
+        following.best = following.best.max(*pair);
+    }
+
+    pub fn count(&self, first: &str, second: &str) -> usize {
+        self.following.get(first)
+            .and_then(|following| following.counts.get(second))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// How many word pairs there are.
+    pub fn len(&self) -> usize {
+        self.following.values().map(|following| following.counts.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.following.is_empty()
+    }
+
+    /// The chance of `second` coming after `first`, given that `second` alone would have a
+    /// chance of `prior`. Pairs that have never been seen fall back towards `BACKOFF * prior`.
+    /// If `second` isn't finished, this is the best it could do once it is.
+    fn probability(&self, first: &str, second: &str, prior: f64, complete: bool) -> f64 {
+        match self.following.get(first) {
+            Some(following) => {
+                let count = match complete {
+                    true => following.counts.get(second).copied().unwrap_or(0),
+                    false => following.best,
+                };
+                (count as f64 + SMOOTHING * BACKOFF * prior) / (following.total as f64 + SMOOTHING)
+            }
+            None => BACKOFF * prior,
+        }
+    }
+}
+
+/// Scores phrases by their log probability under a `BigramModel`: the first word by its
+/// frequency, and every later one by how likely it is to follow the word before it. Word
+/// frequencies are taken relative to the most frequent word.
+///
+/// Until the last word is finished, it's scored as if it turned into the best word that could
+/// follow the one before it, so phrases aren't held back while they're being spelled out.
+pub struct BigramScorer {
+    pub model: Arc<BigramModel>,
+}
+
+impl Scorer for BigramScorer {
+    fn score(&self, candidate: &Candidate, _: &SearchConfig) -> isize {
+        let max = candidate.max_freq.max(1) as f64;
+        let freqs = candidate.word_freqs.iter().copied().chain(Some(candidate.best_freq));
+        let mut log_prob = 0.0;
+        let last = candidate.word_freqs.len();
+        for (i, freq) in freqs.enumerate() {
+            let prior = freq.max(1) as f64 / max;
+            let complete = i < last || candidate.complete;
+            log_prob += match i {
+                0 => prior,
+                _ => self.model.probability(&candidate.words[i - 1], &candidate.words[i], prior, complete),
+            }.ln();
+        }
+        (LOG_SCALE * log_prob) as isize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::Arc;
+
+    use crate::alphabet::Folding;
+    use crate::wordlist::bigrams::{BACKOFF, BigramModel, BigramScorer};
+    use crate::wordlist::trie::scorer::{Candidate, Scorer};
+    use crate::wordlist::trie::searchconfig::SearchConfig;
+
+    #[test]
+    fn read_counts() {
+        let (model, failures) = BigramModel::read(
+            Cursor::new(b"too many 50\nmany secrets 10\n\xff 3\nmany secrets 5\n\nbroken line\nof the lots\nof the 2\n".to_vec()),
+            &Folding::default());
+        assert_eq!(failures, 3);
+        assert_eq!(model.len(), 3);
+        assert_eq!(model.count("MANY", "SECRETS"), 15);
+        assert_eq!(model.count("SECRETS", "MANY"), 0);
+    }
+
+    #[test]
+    fn common_pairs_score_higher() {
+        let mut model = BigramModel::new();
+        model.add("TOO", "MANY", 50);
+        model.add("THE", "OF", 0);
+        model.add("THE", "END", 1000);
+        let scorer = BigramScorer { model: Arc::new(model) };
+        let config = SearchConfig::new();
+        let score = |words: &[&str], freqs: &[usize]| {
+            let words = words.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+            scorer.score(&Candidate {
+                words: &words,
+                word_freqs: &freqs[..freqs.len() - 1],
+                best_freq: freqs[freqs.len() - 1],
+                max_freq: 1000,
+                complete: true,
+            }, &config)
+        };
+        assert!(score(&["TOO", "MANY"], &[100, 100]) > score(&["THE", "OF"], &[1000, 1000]));
+        // an unknown first word leaves the second one with its own frequency, less the backoff
+        assert_eq!(score(&["OF", "THE"], &[1000, 1000]), (1000.0 * BACKOFF.ln()) as isize);
+    }
+}
@@ -0,0 +1,89 @@
+use std::fmt;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::thread::JoinHandle;
+
+use serde::{Deserialize, Serialize};
+
+use crate::wordlist::trie::multithreaded_search::PageRequest;
+use crate::wordlist::trie::searchconfig::PageLimits;
+use crate::wordlist::trie::searchresult::{SearchResult, SearchSummary};
+
+/// A search running on a background thread that hands out its results a page at a time. Between
+/// pages the search is suspended with its frontier intact, so each page continues where the last
+/// one stopped instead of starting over.
+///
+/// The search (and its thread) goes away when this is dropped, along with the snapshot of the
+/// index it was searching.
+pub struct PagedSearch {
+    /// `None` once dropped, so that the search stops waiting for requests
+    requests: Option<Sender<PageRequest>>,
+    pages: Receiver<(Vec<SearchResult>, SearchSummary)>,
+    source_list: Option<String>,
+    thread: Option<JoinHandle<()>>,
+    /// As of the last page
+    summary: SearchSummary,
+}
+
+impl PagedSearch {
+    /// Runs `search` on a new thread, which should answer each request it receives with a page
+    /// and a summary of the search so far. Results are labelled with `source_list`.
+    pub(crate) fn spawn<S>(source_list: Option<String>, search: S) -> PagedSearch
+        where S: FnOnce(Receiver<PageRequest>, Sender<(Vec<SearchResult>, SearchSummary)>) + Send + 'static {
+        let (requests, request_receiver) = channel();
+        let (page_sender, pages) = channel();
+        let thread = thread::spawn(move || search(request_receiver, page_sender));
+        PagedSearch { requests: Some(requests), pages, source_list, thread: Some(thread), summary: SearchSummary::default() }
+    }
+
+    /// What the search has reported about itself, as of the last page.
+    pub fn summary(&self) -> SearchSummary {
+        self.summary
+    }
+
+    /// The next `n` results. Fewer means the search has run out (or out of budget).
+    pub fn next_page(&mut self, n: usize) -> Vec<SearchResult> {
+        self.next_page_with(n, PageLimits::default())
+    }
+
+    /// Like `next_page`, but `limits` can cut this page short without ending the search.
+    pub fn next_page_with(&mut self, n: usize, limits: PageLimits) -> Vec<SearchResult> {
+        let request = PageRequest { size: n, limits };
+        if self.requests.as_ref().is_none_or(|requests| requests.send(request).is_err()) {
+            return vec![];
+        }
+        let (mut page, summary) = self.pages.recv().unwrap_or_default();
+        self.summary = summary;
+        for result in page.iter_mut() {
+            result.source_list = self.source_list.clone();
+        }
+        page
+    }
+}
+
+impl Drop for PagedSearch {
+    /// Ends the search and waits for its thread to finish.
+    fn drop(&mut self) {
+        self.requests.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Names a `PagedSearch` parked in a `Wordlist`, so a client can ask for more results later
+/// (e.g. in a later web request).
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ContinuationToken(pub String);
+
+impl ContinuationToken {
+    pub(crate) fn random() -> ContinuationToken {
+        ContinuationToken(format!("{:016x}", rand::random::<u64>()))
+    }
+}
+
+impl fmt::Display for ContinuationToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
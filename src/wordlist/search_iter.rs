@@ -0,0 +1,83 @@
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Receiver, sync_channel};
+use std::thread;
+use std::thread::JoinHandle;
+
+use crate::wordlist::trie::searchconfig::{CancellationToken, SearchConfig};
+use crate::wordlist::trie::searchresult::{SearchResult, SearchSummary};
+
+/// How many results a search can get ahead of whoever is reading them.
+const BUFFER_SIZE: usize = 64;
+
+pub(crate) type BoxedCallback = Box<dyn FnMut(SearchResult, &SearchConfig) -> bool + Send + Sync>;
+
+/// Results of a search running on a background thread, in the order it finds them.
+///
+/// The search stops after `max_results` results, or as soon as this is dropped.
+pub struct SearchIter {
+    /// `None` once dropped, so that a search waiting to hand over a result gives up
+    receiver: Option<Receiver<SearchResult>>,
+    cancel: CancellationToken,
+    thread: Option<JoinHandle<()>>,
+    /// Set by the search once it's over
+    summary: Arc<Mutex<Option<SearchSummary>>>,
+}
+
+impl SearchIter {
+    /// Runs `search` on a new thread, passing it a callback that feeds this iterator. Results are
+    /// labelled with `source_list`.
+    pub(crate) fn spawn<S>(mut config: SearchConfig, source_list: Option<String>, search: S) -> SearchIter
+        where S: FnOnce(&SearchConfig, BoxedCallback) -> SearchSummary + Send + 'static {
+        let (sender, receiver) = sync_channel(BUFFER_SIZE);
+        // still stops if the caller cancels their own token
+        let cancel = config.cancel.as_ref().map_or_else(CancellationToken::new, CancellationToken::child);
+        config.cancel = Some(cancel.clone());
+        let summary = Arc::new(Mutex::new(None));
+        let search_summary = summary.clone();
+        let thread = thread::spawn(move || {
+            let mut sent = 0;
+            let summary = search(&config, Box::new(move |mut result: SearchResult, config: &SearchConfig| {
+                result.source_list = source_list.clone();
+                if sender.send(result).is_err() {
+                    return true;
+                }
+                sent += 1;
+                sent >= config.max_results.unwrap_or(usize::MAX)
+            }));
+            *search_summary.lock().unwrap() = Some(summary);
+        });
+        SearchIter { receiver: Some(receiver), cancel, thread: Some(thread), summary }
+    }
+
+    /// What the search reported about itself once it was over, which it is by the time this runs
+    /// out of results; `None` while it's still going.
+    pub fn summary(&self) -> Option<SearchSummary> {
+        *self.summary.lock().unwrap()
+    }
+}
+
+impl Iterator for SearchIter {
+    type Item = SearchResult;
+
+    fn next(&mut self) -> Option<SearchResult> {
+        let result = self.receiver.as_ref()?.recv().ok();
+        if result.is_none() {
+            // the results can run out just before the search hands over its summary
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+        }
+        result
+    }
+}
+
+impl Drop for SearchIter {
+    /// Cancels the search and waits for its workers to notice.
+    fn drop(&mut self) {
+        self.cancel.cancel();
+        self.receiver.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
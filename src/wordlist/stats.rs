@@ -0,0 +1,166 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::sync::{Mutex, OnceLock};
+
+use metrics::{GaugeValue, Key, Recorder, Unit};
+
+/// A `metrics` recorder that keeps everything in memory, so the REPL can show it with `stats`.
+/// Searches record:
+///
+/// * `search_nodes_expanded`, `search_pruned_by_freq`, `search_results` and
+///   `search_frontier_discarded`: counters
+/// * `search_queue_retries`: a counter of how often a worker found a queue locked and tried
+///   another
+/// * `search_frontier_size`: a histogram, sampled every `FRONTIER_SAMPLE` expansions
+/// * `search_time_to_first_result`: a histogram, in seconds
+///
+/// all labelled with the kind of query (`regex`, `anagram`, ...). Histograms are kept as a
+/// running `Summary` rather than every value, so they don't grow with the number of searches.
+#[derive(Default)]
+pub struct StatsRecorder {
+    counters: Mutex<BTreeMap<Key, u64>>,
+    gauges: Mutex<BTreeMap<Key, f64>>,
+    histograms: Mutex<BTreeMap<Key, Summary>>,
+}
+
+/// What's been recorded for a histogram.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Summary {
+    pub count: u64,
+    pub sum: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl Summary {
+    fn record(&mut self, value: f64) {
+        if self.count == 0 {
+            self.min = value;
+            self.max = value;
+        } else {
+            self.min = self.min.min(value);
+            self.max = self.max.max(value);
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+
+    pub fn mean(&self) -> f64 {
+        self.sum / self.count as f64
+    }
+}
+
+static RECORDER: OnceLock<StatsRecorder> = OnceLock::new();
+
+/// Installs the global `StatsRecorder` (if no other recorder has been) and returns it.
+pub fn install() -> &'static StatsRecorder {
+    let recorder = RECORDER.get_or_init(StatsRecorder::default);
+    let _ = metrics::set_recorder(recorder);
+    recorder
+}
+
+impl StatsRecorder {
+    pub fn counter(&self, key: &Key) -> u64 {
+        self.counters.lock().unwrap().get(key).copied().unwrap_or(0)
+    }
+
+    /// A summary of what's been recorded for histogram `key`.
+    pub fn histogram(&self, key: &Key) -> Summary {
+        self.histograms.lock().unwrap().get(key).copied().unwrap_or_default()
+    }
+
+    /// Forgets everything recorded so far.
+    pub fn reset(&self) {
+        self.counters.lock().unwrap().clear();
+        self.gauges.lock().unwrap().clear();
+        self.histograms.lock().unwrap().clear();
+    }
+}
+
+impl Recorder for StatsRecorder {
+    fn register_counter(&self, _key: &Key, _unit: Option<Unit>, _description: Option<&'static str>) {}
+
+    fn register_gauge(&self, _key: &Key, _unit: Option<Unit>, _description: Option<&'static str>) {}
+
+    fn register_histogram(&self, _key: &Key, _unit: Option<Unit>, _description: Option<&'static str>) {}
+
+    fn increment_counter(&self, key: &Key, value: u64) {
+        *self.counters.lock().unwrap().entry(key.clone()).or_insert(0) += value;
+    }
+
+    fn update_gauge(&self, key: &Key, value: GaugeValue) {
+        let mut gauges = self.gauges.lock().unwrap();
+        let gauge = gauges.entry(key.clone()).or_insert(0.0);
+        *gauge = value.update_value(*gauge);
+    }
+
+    fn record_histogram(&self, key: &Key, value: f64) {
+        self.histograms.lock().unwrap().entry(key.clone()).or_default().record(value);
+    }
+}
+
+/// `name{label=value,...}`, or just `name` if there are no labels.
+fn key_name(key: &Key) -> String {
+    let labels = key.labels().map(|label| format!("{}={}", label.key(), label.value())).collect::<Vec<_>>();
+    match labels.is_empty() {
+        true => key.name().to_string(),
+        false => format!("{}{{{}}}", key.name(), labels.join(",")),
+    }
+}
+
+impl Display for StatsRecorder {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (key, value) in self.counters.lock().unwrap().iter() {
+            writeln!(f, "{} {}", key_name(key), value)?;
+        }
+        for (key, value) in self.gauges.lock().unwrap().iter() {
+            writeln!(f, "{} {}", key_name(key), value)?;
+        }
+        for (key, summary) in self.histograms.lock().unwrap().iter() {
+            writeln!(f, "{} count {}, mean {:.4}, min {:.4}, max {:.4}",
+                     key_name(key), summary.count, summary.mean(), summary.min, summary.max)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics::{Key, Label, Recorder};
+
+    use crate::wordlist::stats::{install, StatsRecorder, Summary};
+    use crate::wordlist::trie::searchconfig::SearchConfig;
+    use crate::wordlist::trie::trie::Trie;
+
+    #[test]
+    fn summarizes_histograms() {
+        let recorder = StatsRecorder::default();
+        let key = Key::from_parts("sizes", vec![Label::new("query", "regex")]);
+        for x in (1..=10).rev() {
+            recorder.record_histogram(&key, x as f64);
+        }
+        assert_eq!(recorder.histogram(&key), Summary { count: 10, sum: 55.0, min: 1.0, max: 10.0 });
+        recorder.increment_counter(&Key::from_name("things"), 2);
+        recorder.increment_counter(&Key::from_name("things"), 3);
+        assert_eq!(recorder.counter(&Key::from_name("things")), 5);
+        assert_eq!(recorder.to_string(),
+                   "things 5\nsizes{query=regex} count 10, mean 5.5000, min 1.0000, max 10.0000\n");
+        recorder.reset();
+        assert_eq!(recorder.to_string(), "");
+    }
+
+    #[test]
+    fn searches_record_stats() {
+        let recorder = install();
+        let mut trie = Trie::default();
+        trie.add_all(vec!["HELLO", "HELP", "GOODBYE"]);
+        let trie = trie.build();
+        trie.query_regex_results("HEL(LO|P)", &SearchConfig::new());
+
+        // other tests may be searching at the same time, so only check that these went up
+        let key = |name: &'static str| Key::from_parts(name, vec![Label::new("query", "regex")]);
+        assert!(recorder.counter(&key("search_nodes_expanded")) >= 6);
+        assert!(recorder.counter(&key("search_results")) >= 2);
+        assert!(recorder.histogram(&key("search_time_to_first_result")).count > 0);
+    }
+}
@@ -1,168 +1,315 @@
-use std::collections::{BinaryHeap, HashSet};
-
-use std::ops::{Deref};
-use std::sync::{Arc, TryLockResult};
-use std::sync::{Mutex, MutexGuard};
-use std::sync::atomic::AtomicIsize;
-use std::sync::atomic::Ordering::Relaxed;
-use rand::{Rng, thread_rng};
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::atomic::Ordering::SeqCst;
 
+#[cfg(not(loom))]
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, atomic::{AtomicBool, AtomicUsize}};
+#[cfg(loom)]
+use loom::sync::{Arc, Condvar, Mutex, MutexGuard, atomic::{AtomicBool, AtomicUsize}};
 
-// register_counter!("pq_ops", "ops");
-// register_gauge!("pq_size", "size");
-// register_histogram!("tries_to_find_queue", "tries");
-// register_histogram!("tries_to_find_empty_queue", "tries");
+#[cfg(not(loom))]
+use std::cell::Cell;
+#[cfg(not(loom))]
+use rand::{Rng, thread_rng};
 
+/// A relaxed concurrent priority queue, in the style of a MultiQueue: items are spread over
+/// several locked heaps, and a pop takes the better of the tops of two of them, so it usually
+/// (but not always) gets one of the best items. Each thread sticks to the same heap for a few
+/// operations in a row, which keeps threads out of each other's way.
+///
+/// Besides `try_pop`, there's a blocking `pop` for workers that push what they get from it back
+/// on: it waits for an item while other workers still have one, and returns `None` once the
+/// queue is closed, which happens when nobody has an item left (or on `close`).
 pub struct ConcurrentPQ<T: QItem> {
-    pq: Arc<Vec<MutexQ<T>>>,
-    len: Arc<AtomicIsize>,
-    num_queues: usize,
-    available: Arc<Mutex<HashSet<usize>>>,
+    shared: Arc<Shared<T>>,
 }
 
 pub trait QItem: Send + Ord + Eq + PartialOrd + PartialEq {}
 
-type SingleQ<T> = BinaryHeap<T>;
-
-struct MutexQ<T: QItem> {
-    queue: Mutex<SingleQ<T>>,
+struct Shared<T> {
+    queues: Vec<Mutex<BinaryHeap<T>>>,
+    /// How many items are in `queues`; only changed with the heap in question locked
+    len: AtomicUsize,
+    /// Items taken by `pop` and not yet `done`, plus `pop`s looking for one
+    in_flight: AtomicUsize,
+    closed: AtomicBool,
+    /// `pop`s waiting on `wake`
+    waiting: AtomicUsize,
+    wait_lock: Mutex<()>,
+    wake: Condvar,
+    /// How many times a heap was found locked and another one tried
+    retries: AtomicUsize,
+    /// Picks heaps in turn under loom, where picking them at random would make runs differ
+    #[cfg(loom)]
+    next: AtomicUsize,
 }
 
-struct HeldQ<'a, T: QItem> {
-    queue: MutexGuard<'a, SingleQ<T>>,
+/// How many operations in a row a thread uses the same heap for.
+#[cfg(not(loom))]
+const STICKINESS: usize = 8;
+
+#[cfg(not(loom))]
+thread_local! {
+    /// This thread's current heap (modulo the number of heaps) and how many more uses it has
+    static STICKY: Cell<(usize, usize)> = const { Cell::new((0, 0)) };
 }
 
-impl<'a, T: QItem> HeldQ<'a, T> {
-    fn from_result(result: TryLockResult<MutexGuard<'a, SingleQ<T>>>) -> Option<HeldQ<'a, T>> {
-        match result {
-            Ok(x) => Some(HeldQ { queue: x }),
-            Err(_) => None
-        }
-    }
-    fn from_guard(guard: MutexGuard<'a, SingleQ<T>>) -> HeldQ<'a, T> {
-        HeldQ { queue: guard }
+impl<T: QItem> Clone for ConcurrentPQ<T> {
+    fn clone(&self) -> Self {
+        ConcurrentPQ { shared: self.shared.clone() }
     }
 }
 
-impl<T: QItem> MutexQ<T> {
-    fn new() -> MutexQ<T> {
-        MutexQ { queue: Mutex::new(SingleQ::new()) }
+impl<T: QItem> ConcurrentPQ<T> {
+    /// A queue for `workers` workers: two heaps each, or just one heap if there's only one
+    /// worker, so that it pops in exactly best-first order.
+    pub fn for_workers(workers: usize) -> ConcurrentPQ<T> {
+        match workers {
+            1 => Self::with_queues(1),
+            workers => Self::with_queues(workers * 2),
+        }
     }
-    fn get(&self) -> HeldQ<T> {
-        HeldQ::from_guard(self.queue.lock().unwrap())
+
+    pub fn with_queues(n: usize) -> ConcurrentPQ<T> {
+        ConcurrentPQ {
+            shared: Arc::new(Shared {
+                queues: (0..n.max(1)).map(|_| Mutex::new(BinaryHeap::new())).collect(),
+                len: AtomicUsize::new(0),
+                in_flight: AtomicUsize::new(0),
+                closed: AtomicBool::new(false),
+                waiting: AtomicUsize::new(0),
+                wait_lock: Mutex::new(()),
+                wake: Condvar::new(),
+                retries: AtomicUsize::new(0),
+                #[cfg(loom)]
+                next: AtomicUsize::new(0),
+            })
+        }
     }
-    fn try_get(&self) -> Option<HeldQ<T>> {
-        HeldQ::from_result(self.queue.try_lock())
+
+    fn num_queues(&self) -> usize {
+        self.shared.queues.len()
     }
-}
 
+    #[cfg(not(loom))]
+    fn random_index(&self) -> usize {
+        thread_rng().gen_range(0..self.num_queues())
+    }
 
-impl<T: QItem> Clone for ConcurrentPQ<T> {
-    fn clone(&self) -> Self {
-        ConcurrentPQ { pq: self.pq.clone(), len: self.len.clone(), num_queues: self.num_queues, available: self.available.clone() }
+    #[cfg(loom)]
+    fn random_index(&self) -> usize {
+        self.shared.next.fetch_add(1, SeqCst) % self.num_queues()
     }
-}
 
-impl<T: QItem> ConcurrentPQ<T> {
-    pub fn new() -> ConcurrentPQ<T> {
-        let n = rayon::current_num_threads() * 2;
-        let mut v = Vec::with_capacity(n);
-        let mut available = HashSet::new();
-        for i in 0..n {
-            v.push(MutexQ::new());
-            available.insert(i);
-        }
-        let pq = ConcurrentPQ {
-            pq: Arc::new(v),
-            len: Arc::new(AtomicIsize::new(0)),
-            num_queues: n,
-            available: Arc::new(Mutex::new(available)),
+    /// This thread's current heap.
+    #[cfg(not(loom))]
+    fn sticky_index(&self) -> usize {
+        let (index, uses) = match STICKY.with(|sticky| sticky.get()) {
+            (_, 0) => (self.random_index(), STICKINESS),
+            sticky => sticky,
         };
-        pq
+        STICKY.with(|sticky| sticky.set((index, uses - 1)));
+        index % self.num_queues()
     }
 
-    fn rand_index(&self) -> usize {
-        thread_rng().gen_range(0..self.pq.len())
+    #[cfg(loom)]
+    fn sticky_index(&self) -> usize {
+        self.random_index()
     }
-    fn try_get_queue(&self) -> Option<HeldQ<T>> {
-        let idx = self.rand_index();
-        let m: &MutexQ<T> = self.pq.get(idx).unwrap();
-        let held = m.try_get();
-        held
+
+    /// Moves this thread on to another heap, after finding its current one busy.
+    fn unstick(&self, index: usize) {
+        #[cfg(not(loom))]
+        STICKY.with(|sticky| sticky.set((index, STICKINESS)));
+        #[cfg(loom)]
+        let _ = index;
     }
 
-    fn get_queue(&self) -> HeldQ<T> {
-        //let tries = 1;
-        let mut result = self.try_get_queue();
-        while result.is_none() {
-            //tries += 1;
-            result = self.try_get_queue();
+    /// A random heap other than `index`, if there is one.
+    fn other_index(&self, index: usize) -> Option<usize> {
+        match self.num_queues() {
+            1 => None,
+            n => Some((index + 1 + self.random_index() % (n - 1)) % n),
         }
-        result.unwrap()
     }
 
-    fn get_nonempty_queue(&self, claimed: usize) -> Option<HeldQ<T>> {
-        let mut q = self.get_queue();
-        while q.queue.is_empty() {
-            if self.len.load(Relaxed) <= claimed as isize {
-                return None;
+    /// Locks this thread's current heap, or another one if it's busy, and returns it with its
+    /// index. Tries each heap once before waiting for one, rather than spinning.
+    fn lock_any(&self) -> (usize, MutexGuard<'_, BinaryHeap<T>>) {
+        let mut index = self.sticky_index();
+        for _ in 1..self.num_queues() {
+            if let Ok(queue) = self.shared.queues[index].try_lock() {
+                return (index, queue);
             }
-            q = self.get_queue()
+            self.shared.retries.fetch_add(1, SeqCst);
+            index = (index + 1) % self.num_queues();
+            self.unstick(index);
         }
-        Some(q)
+        (index, self.shared.queues[index].lock().unwrap())
     }
 
-    pub fn push(&self, item: T) {
-        self.get_queue().queue.push(item);
-        self.len.fetch_add(1, Relaxed);
+    /// How many items are queued.
+    pub fn len(&self) -> usize {
+        self.shared.len.load(SeqCst)
     }
 
-    pub fn clear(&mut self) {
-        for q in self.pq.deref() {
-            q.queue.lock().unwrap().clear();
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// How many times a heap was found locked since this was last called.
+    pub fn take_retries(&self) -> usize {
+        self.shared.retries.swap(0, SeqCst)
+    }
+
+    pub fn push(&self, item: T) {
+        {
+            // `len` only changes with a heap locked, so an item is always counted before it's
+            // popped and `len` can't go below 0
+            let (_, mut queue) = self.lock_any();
+            queue.push(item);
+            self.shared.len.fetch_add(1, SeqCst);
+        }
+        if self.shared.waiting.load(SeqCst) > 0 {
+            // taking the lock means a `pop` that saw the queue empty is now waiting
+            let _guard = self.shared.wait_lock.lock().unwrap();
+            self.shared.wake.notify_one();
         }
     }
 
-    pub fn try_pop(&mut self) -> Option<T> {
-        if self.len.load(Relaxed) <= 0 { return None; }
-        let mut q1 = self.get_nonempty_queue(0);
-        let q1_sz = q1.as_ref().map(|q| q.queue.len()).unwrap_or(0);
-        let mut q2 = self.get_queue();
-        if q2.queue.is_empty() && q1.is_none() {
+    /// Takes a good item (the better of the tops of two heaps) if there is one. Only returns
+    /// `None` if every heap was empty when it looked.
+    pub fn try_pop(&self) -> Option<T> {
+        if self.is_empty() {
             return None;
         }
-        self.len.fetch_add(-1, Relaxed);
-        if q1.is_none() {
-            return q2.queue.pop();
+        {
+            let (index, mut first) = self.lock_any();
+            // only one heap is ever waited for, so nothing can deadlock with `retain_best`
+            let mut second = self.other_index(index).and_then(|other| self.shared.queues[other].try_lock().ok());
+            let better = match second.as_mut() {
+                Some(second) if second.peek() > first.peek() => second,
+                _ => &mut first,
+            };
+            if let Some(item) = better.pop() {
+                self.shared.len.fetch_sub(1, SeqCst);
+                return Some(item);
+            }
         }
-        if q2.queue.is_empty() {
-            return q1.unwrap().queue.pop();
+        // the heaps looked at were empty, but others may not be
+        for queue in &self.shared.queues {
+            if let Some(item) = queue.lock().unwrap().pop() {
+                self.shared.len.fetch_sub(1, SeqCst);
+                return Some(item);
+            }
         }
+        None
+    }
+
+    /// Takes a good item, waiting for one if the queue is empty but other `pop`ped items
+    /// haven't been `done` yet (since handling them may push more). Returns `None` once the
+    /// queue is closed; it closes itself when it's empty and every popped item is `done`.
+    ///
+    /// Every item this returns must be passed to `done` once it's been handled.
+    pub fn pop(&self) -> Option<T> {
+        let shared = &self.shared;
+        loop {
+            if shared.closed.load(SeqCst) {
+                return None;
+            }
+            // counted before looking, so nobody sees the queue empty with no items in flight
+            // while this is taking one
+            shared.in_flight.fetch_add(1, SeqCst);
+            if let Some(item) = self.try_pop() {
+                return Some(item);
+            }
+            if shared.in_flight.fetch_sub(1, SeqCst) == 1 && self.is_empty() {
+                self.close();
+                return None;
+            }
+            let guard = shared.wait_lock.lock().unwrap();
+            shared.waiting.fetch_add(1, SeqCst);
+            if self.is_empty() && !shared.closed.load(SeqCst) && shared.in_flight.load(SeqCst) > 0 {
+                drop(shared.wake.wait(guard).unwrap());
+            } else {
+                drop(guard);
+            }
+            shared.waiting.fetch_sub(1, SeqCst);
+        }
+    }
+
+    /// Marks an item from `pop` as handled, closing the queue if it was the last one and
+    /// nothing is left.
+    pub fn done(&self) {
+        if self.shared.in_flight.fetch_sub(1, SeqCst) == 1 && self.is_empty() {
+            self.close();
+        }
+    }
+
+    /// Makes `pop` return `None` (waking any waiting) until `reopen`. Queued items are kept.
+    pub fn close(&self) {
+        self.shared.closed.store(true, SeqCst);
+        let _guard = self.shared.wait_lock.lock().unwrap();
+        self.shared.wake.notify_all();
+    }
 
-        if q1.as_ref().unwrap().queue.peek() > q2.queue.peek() {
-            q1.unwrap().queue.pop()
-        } else { q2.queue.pop() }
+    pub fn reopen(&self) {
+        self.shared.closed.store(false, SeqCst);
+    }
+
+    pub fn clear(&self) {
+        for queue in &self.shared.queues {
+            let mut queue = queue.lock().unwrap();
+            self.shared.len.fetch_sub(queue.len(), SeqCst);
+            queue.clear();
+        }
+    }
+
+    /// Throws away all but the best `limit` items in each group (as `group` sorts them into),
+    /// returning how many were thrown away.
+    pub fn retain_best<G>(&self, limit: usize, group: G) -> usize
+        where G: Fn(&T) -> usize {
+        // always locked in the same order, so two of these can't deadlock
+        let mut queues = self.shared.queues.iter().map(|q| q.lock().unwrap()).collect::<Vec<_>>();
+        let mut items = queues.iter_mut().flat_map(|q| q.drain().collect::<Vec<_>>()).collect::<Vec<_>>();
+        items.sort_unstable_by(|a, b| b.cmp(a));
+        let total = items.len();
+        let mut kept = 0;
+        let num_queues = queues.len();
+        let mut counts = HashMap::new();
+        for item in items {
+            let count = counts.entry(group(&item)).or_insert(0);
+            if *count < limit {
+                *count += 1;
+                queues[kept % num_queues].push(item);
+                kept += 1;
+            }
+        }
+        self.shared.len.fetch_sub(total - kept, SeqCst);
+        total - kept
     }
 }
 
 
-#[cfg(test)]
+#[cfg(all(test, not(loom)))]
 mod tests {
-    use std::sync::atomic::Ordering::Relaxed;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::SeqCst;
+    use std::thread;
+
     use crate::wordlist::trie::concurrent_pq::{ConcurrentPQ, QItem};
 
     impl QItem for i32 {}
 
     #[test]
     fn test_pq() {
-        let mut pq = ConcurrentPQ::new();
+        let pq = ConcurrentPQ::for_workers(rayon::current_num_threads());
         pq.push(5);
         pq.push(2);
         pq.push(7);
         pq.push(3);
         pq.push(1);
 
-        assert_eq!(pq.len.load(Relaxed), 5);
+        assert_eq!(pq.len(), 5);
 
         let mut popped = vec![];
         while let Some(x) = pq.try_pop() {
@@ -171,5 +318,146 @@ mod tests {
 
         popped.sort(); // popping is slightly non-deterministic so we're not guaranteed an order
         assert_eq!(popped, vec![1, 2, 3, 5, 7]);
+        assert_eq!(pq.len(), 0);
+    }
+
+    #[test]
+    fn retain_best() {
+        let pq = ConcurrentPQ::for_workers(rayon::current_num_threads());
+        for x in 1..=10 {
+            pq.push(x);
+        }
+        // the best two odd and the best two even
+        assert_eq!(pq.retain_best(2, |x| (x % 2) as usize), 6);
+        assert_eq!(pq.len(), 4);
+        let mut popped = vec![];
+        while let Some(x) = pq.try_pop() {
+            popped.push(x);
+        }
+        popped.sort();
+        assert_eq!(popped, vec![7, 8, 9, 10]);
+    }
+
+    #[test]
+    fn pop_until_everything_is_done() {
+        // each item x > 0 pushes two x - 1s, so each 5 makes 1 + 2 + 4 + ... + 32 = 63 pops
+        let pq = ConcurrentPQ::with_queues(4);
+        for _ in 0..3 {
+            pq.push(5);
+        }
+        let popped = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| while let Some(x) = pq.pop() {
+                    popped.fetch_add(1, SeqCst);
+                    if x > 0 {
+                        pq.push(x - 1);
+                        pq.push(x - 1);
+                    }
+                    pq.done();
+                });
+            }
+        });
+        assert_eq!(popped.load(SeqCst), 3 * 63);
+        assert!(pq.is_empty());
+        assert_eq!(pq.pop(), None);
+    }
+
+    #[test]
+    fn close_keeps_items() {
+        let pq = ConcurrentPQ::with_queues(2);
+        pq.push(1);
+        pq.push(2);
+        assert_eq!(pq.pop(), Some(2));
+        pq.close();
+        assert_eq!(pq.pop(), None);
+        pq.done();
+        pq.reopen();
+        assert_eq!(pq.pop(), Some(1));
+        pq.done();
+        assert_eq!(pq.pop(), None);
+        // closed now, so this stays put
+        pq.push(3);
+        assert_eq!(pq.pop(), None);
+        assert_eq!(pq.len(), 1);
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use loom::sync::Arc;
+    use loom::thread;
+
+    use crate::wordlist::trie::concurrent_pq::{ConcurrentPQ, QItem};
+
+    impl QItem for i32 {}
+
+    /// Run with `RUSTFLAGS="--cfg loom" cargo test --release --lib loom`.
+    fn model<F: Fn() + Sync + Send + 'static>(f: F) {
+        let mut builder = loom::model::Builder::new();
+        builder.preemption_bound = Some(3);
+        builder.check(f);
+    }
+
+    #[test]
+    fn no_lost_items() {
+        model(|| {
+            let pq = Arc::new(ConcurrentPQ::with_queues(2));
+            let pushers = (1..=2).map(|x| {
+                let pq = pq.clone();
+                thread::spawn(move || pq.push(x))
+            }).collect::<Vec<_>>();
+            let popped = pq.try_pop();
+            for pusher in pushers {
+                pusher.join().unwrap();
+            }
+            let mut all = popped.into_iter().chain(pq.try_pop()).chain(pq.try_pop()).collect::<Vec<_>>();
+            all.sort();
+            assert_eq!(all, vec![1, 2]);
+            assert!(pq.is_empty());
+        });
+    }
+
+    #[test]
+    fn workers_finish() {
+        model(|| {
+            // 1 pushes 0, so two items get popped between the workers
+            let pq = Arc::new(ConcurrentPQ::with_queues(2));
+            pq.push(1);
+            let workers = (0..2).map(|_| {
+                let pq = pq.clone();
+                thread::spawn(move || {
+                    let mut popped = 0;
+                    while let Some(x) = pq.pop() {
+                        if x > 0 {
+                            pq.push(x - 1);
+                        }
+                        pq.done();
+                        popped += 1;
+                    }
+                    popped
+                })
+            }).collect::<Vec<_>>();
+            let popped: i32 = workers.into_iter().map(|x| x.join().unwrap()).sum();
+            assert_eq!(popped, 2);
+            assert!(pq.is_empty());
+        });
+    }
+
+    #[test]
+    fn close_wakes_waiting_pop() {
+        model(|| {
+            let pq = Arc::new(ConcurrentPQ::with_queues(2));
+            pq.push(1);
+            // holding an item makes the other pop wait rather than finish
+            assert_eq!(pq.pop(), Some(1));
+            let waiter = {
+                let pq = pq.clone();
+                thread::spawn(move || pq.pop())
+            };
+            pq.close();
+            assert_eq!(waiter.join().unwrap(), None);
+            pq.done();
+        });
     }
 }
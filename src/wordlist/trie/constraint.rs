@@ -0,0 +1,281 @@
+use std::collections::HashMap;
+
+use crate::regex::nfa::graph::NfaGraph;
+use crate::regex::nfa::state::NfaStateKind::Accept;
+use crate::regex::nfa::state::NfaStatePtr;
+use crate::regex::parse::{pattern, SEPARATOR};
+use crate::wordlist::trie::trie::ImmutableTrie;
+
+/// A condition on the words a search finds, checked a letter at a time as the search walks down
+/// the trie, so that branches no word can satisfy are never explored.
+///
+/// Letters are the index's (uppercase, after folding). Spaces between words go to `space`
+/// rather than `step`; by default a phrase is checked as if it were one long word.
+pub trait Constraint: Send + Sync {
+    /// What the constraint remembers about the letters so far.
+    type State: Send;
+
+    /// The state before any letters.
+    fn start(&self) -> Self::State;
+
+    /// The state after `letter`, or `None` if nothing starting this way can be accepted.
+    fn step(&self, state: &Self::State, letter: char) -> Option<Self::State>;
+
+    /// Whether the letters that led to `state` make an acceptable answer.
+    fn accept(&self, state: &Self::State) -> bool;
+
+    /// The state after a space between words, or `None` if a word can't end here. Spaces
+    /// don't count by default.
+    fn space(&self, state: Self::State) -> Option<Self::State> {
+        Some(state)
+    }
+
+    /// Added to the priority of candidates in `state`, to steer the search towards promising
+    /// ones. It doesn't change results' scores.
+    fn heuristic(&self, _state: &Self::State) -> isize {
+        0
+    }
+
+    /// What kind of query this is, to label its stats with.
+    fn kind(&self) -> &'static str {
+        "custom"
+    }
+
+    /// A constraint that needs both this one and `other` to accept.
+    fn and<C: Constraint>(self, other: C) -> And<Self, C> where Self: Sized {
+        And(self, other)
+    }
+
+    /// A constraint that needs either this one or `other` to accept.
+    fn or<C: Constraint>(self, other: C) -> Or<Self, C> where Self: Sized {
+        Or(self, other)
+    }
+}
+
+/// Both constraints; see `Constraint::and`. Heuristics are added up.
+pub struct And<A, B>(pub A, pub B);
+
+impl<A: Constraint, B: Constraint> Constraint for And<A, B> {
+    type State = (A::State, B::State);
+
+    fn start(&self) -> Self::State {
+        (self.0.start(), self.1.start())
+    }
+
+    fn step(&self, state: &Self::State, letter: char) -> Option<Self::State> {
+        Some((self.0.step(&state.0, letter)?, self.1.step(&state.1, letter)?))
+    }
+
+    fn accept(&self, state: &Self::State) -> bool {
+        self.0.accept(&state.0) && self.1.accept(&state.1)
+    }
+
+    fn space(&self, state: Self::State) -> Option<Self::State> {
+        Some((self.0.space(state.0)?, self.1.space(state.1)?))
+    }
+
+    fn heuristic(&self, state: &Self::State) -> isize {
+        self.0.heuristic(&state.0) + self.1.heuristic(&state.1)
+    }
+
+    fn kind(&self) -> &'static str {
+        "combined"
+    }
+}
+
+/// Either constraint; see `Constraint::or`. Each side is followed until it gives up, and the
+/// heuristic is the better of the sides still going.
+pub struct Or<A, B>(pub A, pub B);
+
+impl<A: Constraint, B: Constraint> Constraint for Or<A, B> {
+    type State = (Option<A::State>, Option<B::State>);
+
+    fn start(&self) -> Self::State {
+        (Some(self.0.start()), Some(self.1.start()))
+    }
+
+    fn step(&self, state: &Self::State, letter: char) -> Option<Self::State> {
+        let a = state.0.as_ref().and_then(|a| self.0.step(a, letter));
+        let b = state.1.as_ref().and_then(|b| self.1.step(b, letter));
+        match (&a, &b) {
+            (None, None) => None,
+            _ => Some((a, b)),
+        }
+    }
+
+    fn accept(&self, state: &Self::State) -> bool {
+        state.0.as_ref().is_some_and(|a| self.0.accept(a))
+            || state.1.as_ref().is_some_and(|b| self.1.accept(b))
+    }
+
+    fn space(&self, state: Self::State) -> Option<Self::State> {
+        match (state.0.and_then(|a| self.0.space(a)), state.1.and_then(|b| self.1.space(b))) {
+            (None, None) => None,
+            (a, b) => Some((a, b)),
+        }
+    }
+
+    fn heuristic(&self, state: &Self::State) -> isize {
+        let a = state.0.as_ref().map(|a| self.0.heuristic(a));
+        let b = state.1.as_ref().map(|b| self.1.heuristic(b));
+        a.into_iter().chain(b).max().unwrap_or(0)
+    }
+
+    fn kind(&self) -> &'static str {
+        "combined"
+    }
+}
+
+/// Matches a regex (in the syntax `query_regex` takes). If the regex has word separators in it
+/// (`_` or a space, as in `T..MANY_S.CRETS`), spaces in a phrase have to match them; otherwise
+/// they can go anywhere.
+pub struct Regex {
+    nfa: NfaGraph,
+    separators: bool,
+}
+
+impl Regex {
+    pub fn new(regex: &str) -> Regex {
+        Regex {
+            nfa: NfaGraph::from_regex(regex),
+            separators: pattern(regex).is_ok_and(|(_, pattern)| pattern.has_separators()),
+        }
+    }
+}
+
+impl Constraint for Regex {
+    type State = Vec<NfaStatePtr>;
+
+    fn start(&self) -> Self::State {
+        self.nfa.starting_states()
+    }
+
+    fn step(&self, state: &Self::State, letter: char) -> Option<Self::State> {
+        let result = self.nfa.apply_with_start(&letter.to_string(), state);
+        if result.states.is_empty() {
+            None
+        } else {
+            Some(result.states)
+        }
+    }
+
+    fn accept(&self, state: &Self::State) -> bool {
+        state.iter().any(|x| x.kind_is(&Accept))
+    }
+
+    fn space(&self, state: Self::State) -> Option<Self::State> {
+        match self.separators {
+            true => self.step(&state, SEPARATOR),
+            false => Some(state),
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        "regex"
+    }
+}
+
+/// Uses up exactly the given letters.
+pub struct Anagram {
+    counts: HashMap<char, usize>,
+}
+
+impl Anagram {
+    pub fn new(letters: &str) -> Anagram {
+        Anagram { counts: ImmutableTrie::get_counts(letters) }
+    }
+}
+
+impl Constraint for Anagram {
+    /// How many of each letter are left
+    type State = HashMap<char, usize>;
+
+    fn start(&self) -> Self::State {
+        self.counts.clone()
+    }
+
+    fn step(&self, counts: &Self::State, letter: char) -> Option<Self::State> {
+        if *counts.get(&letter).unwrap_or(&0) > 0 {
+            let mut new_counts = counts.clone();
+            *new_counts.get_mut(&letter).unwrap() -= 1;
+            Some(new_counts)
+        } else { None }
+    }
+
+    fn accept(&self, counts: &Self::State) -> bool {
+        counts.values().all(|x| *x == 0)
+    }
+
+    fn kind(&self) -> &'static str {
+        "anagram"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::wordlist::trie::constraint::{Anagram, Constraint, Regex};
+    use crate::wordlist::trie::searchconfig::SearchConfig;
+    use crate::wordlist::trie::searchresult::SearchResult;
+    use crate::wordlist::trie::trie::Trie;
+
+    /// Words whose letters (A=1 ... Z=26) add up to a given total.
+    struct LetterSum(usize);
+
+    impl Constraint for LetterSum {
+        type State = usize;
+
+        fn start(&self) -> usize {
+            0
+        }
+
+        fn step(&self, sum: &usize, letter: char) -> Option<usize> {
+            let sum = sum + (letter as usize - 'A' as usize + 1);
+            if sum <= self.0 { Some(sum) } else { None }
+        }
+
+        fn accept(&self, sum: &usize) -> bool {
+            *sum == self.0
+        }
+    }
+
+    #[test]
+    fn combine_constraints() {
+        let mut trie = Trie::default();
+        trie.add_all(vec!["HELLO", "HELP", "HELM", "GOOD", "DOG"]);
+        let trie = trie.build();
+        let query = |constraint: &dyn Fn(&SearchConfig) -> Vec<SearchResult>| {
+            let mut result = constraint(&SearchConfig::new()).iter().map(|x| x.text()).collect::<Vec<_>>();
+            result.sort();
+            result
+        };
+
+        // HELP = 8 + 5 + 12 + 16 and GOOD = 7 + 15 + 15 + 4
+        assert_eq!(query(&|config| trie.query_results(&LetterSum(41), config)), vec!["GOOD", "HELP"]);
+        assert_eq!(query(&|config| trie.query_results(&Regex::new("HEL(LO|M|P)").and(LetterSum(41)), config)),
+                   vec!["HELP"]);
+        assert_eq!(query(&|config| trie.query_results(&Regex::new("HELLO").or(Anagram::new("GOD")), config)),
+                   vec!["DOG", "HELLO"]);
+        assert!(query(&|config| trie.query_results(&Anagram::new("GOD").and(LetterSum(1)), config)).is_empty());
+    }
+
+    #[test]
+    fn separators_place_spaces() {
+        let mut trie = Trie::default();
+        trie.add_all(vec!["TOO", "MANY", "TOOMANY", "SECRETS", "MANYS", "ECRETS"]);
+        let trie = trie.build();
+        let mut config = SearchConfig::new();
+        config.space_penalty = Some(5);
+        config.spaces_allowed = 2;
+        let query = |regex: &str| {
+            let mut result = trie.query_results(&Regex::new(regex), &config).iter().map(|x| x.text()).collect::<Vec<_>>();
+            result.sort();
+            result
+        };
+
+        assert_eq!(query("T..MANYS.CRETS"), vec!["TOO MANY SECRETS", "TOO MANYS ECRETS", "TOOMANY SECRETS"]);
+        assert_eq!(query("T.._MANY_S.CRETS"), vec!["TOO MANY SECRETS"]);
+        assert_eq!(query("T.._?MANY S.CRETS"), vec!["TOO MANY SECRETS", "TOOMANY SECRETS"]);
+        assert_eq!(query("T..MANY"), vec!["TOO MANY", "TOOMANY"]);
+        assert!(query("T.._MANY_").is_empty());
+    }
+}
@@ -0,0 +1,45 @@
+use rayon::scope;
+
+/// Runs the workers of a best-first search. Every search goes through the same core
+/// (`ImmutableTrie::query_with`); this only decides how many threads it gets.
+pub trait Executor: Sync {
+    /// How many workers `run` runs at once.
+    fn threads(&self) -> usize;
+
+    /// Runs `worker` on `threads()` threads at once, returning once they all have.
+    fn run(&self, worker: &(dyn Fn() + Sync));
+}
+
+/// Runs the search on the calling thread. With one worker and one queue, candidates are
+/// explored in exactly best-first order.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct SingleThreaded;
+
+impl Executor for SingleThreaded {
+    fn threads(&self) -> usize {
+        1
+    }
+
+    fn run(&self, worker: &(dyn Fn() + Sync)) {
+        worker();
+    }
+}
+
+/// Runs a worker on every thread of the current rayon pool, including the calling one.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct ThreadPool;
+
+impl Executor for ThreadPool {
+    fn threads(&self) -> usize {
+        rayon::current_num_threads()
+    }
+
+    fn run(&self, worker: &(dyn Fn() + Sync)) {
+        scope(|scope| {
+            for _ in 1..self.threads() {
+                scope.spawn(|_| worker());
+            }
+            worker();
+        });
+    }
+}
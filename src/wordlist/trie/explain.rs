@@ -0,0 +1,206 @@
+use std::fmt::{Display, Formatter};
+
+use crate::wordlist::trie::constraint::Constraint;
+use crate::wordlist::trie::executor::SingleThreaded;
+use crate::wordlist::trie::scorer::Candidate;
+use crate::wordlist::trie::searchconfig::SearchConfig;
+use crate::wordlist::trie::trie::ImmutableTrie;
+
+/// Whether a search finds a particular word (or phrase), and if not, the first reason it
+/// doesn't; see `ImmutableTrie::explain`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Explanation {
+    /// The search finds it as its `rank`th result (counting from 0)
+    Found { rank: usize, score: isize },
+    /// `word` isn't in the wordlist
+    NotInList { word: String },
+    /// `word` has been banned
+    Banned { word: String },
+    /// `word` doesn't have all the tags the search asks for
+    MissingTags { word: String },
+    /// `word` (or every word starting the same way) isn't more frequent than `prune_freq`
+    BelowPruneFreq { word: String, freq: usize },
+    /// The phrase has more spaces than `spaces_allowed` (none at all without a `space_penalty`)
+    TooManySpaces { spaces: usize, allowed: usize },
+    /// `word` is shorter than `min_word_len`
+    BelowMinWordLen { word: String, len: usize },
+    /// `word` is longer than `max_word_len`
+    AboveMaxWordLen { word: String, len: usize },
+    /// The phrase has `len` letters, outside `min_length` to `max_length`
+    WrongLength { len: usize },
+    /// The query rules out the letter or space at `position` (counting from 0, spaces included)
+    PatternMismatch { position: usize },
+    /// The query wants more letters than the phrase has
+    PatternUnfinished,
+    /// It matches, but the search finds `max_results` results before it
+    RankedPastMaxResults { score: isize, max_results: usize },
+    /// It matches, but the search stops (at its deadline or budget, or by discarding it from a
+    /// limited frontier) before finding it
+    NotReached { score: isize },
+}
+
+impl ImmutableTrie {
+    /// Walks `phrase` (words of the index's letters, separated by single spaces) the way a
+    /// search for `constraint` with `config` would, and says where it falls out, or else where
+    /// it's ranked. Ranks are those of a single-threaded search; a multithreaded one finds the
+    /// same results, but may not return them in quite the same order.
+    pub fn explain<C: Constraint>(&self, phrase: &str, constraint: &C, config: &SearchConfig) -> Explanation {
+        let words: Vec<String> = phrase.split(' ').map(|word| word.to_string()).collect();
+        let mut word_freqs = vec![];
+        let mut state = constraint.start();
+        let mut position = 0;
+
+        for (i, word) in words.iter().enumerate() {
+            if i > 0 {
+                let allowed = if config.space_penalty.is_some() { config.spaces_allowed } else { 0 };
+                if i > allowed {
+                    return Explanation::TooManySpaces { spaces: i, allowed };
+                }
+                state = match constraint.space(state) {
+                    Some(next) => next,
+                    None => return Explanation::PatternMismatch { position },
+                };
+                position += 1;
+            }
+
+            let mut node = self.root();
+            let mut lightest = usize::MAX;
+            for letter in word.chars() {
+                match self.get_child(node, letter) {
+                    Some(child) => node = child,
+                    None => return Explanation::NotInList { word: word.clone() },
+                }
+                lightest = lightest.min(node.data.weight);
+            }
+            if !node.is_terminal || word.is_empty() {
+                return Explanation::NotInList { word: word.clone() };
+            }
+            if node.data.suppressed {
+                return Explanation::Banned { word: word.clone() };
+            }
+            if config.tags.is_some_and(|tags| !tags.matches(node.data.tags)) {
+                return Explanation::MissingTags { word: word.clone() };
+            }
+            // the search skips light branches, and only returns a word heavier than `prune_freq`
+            let last = i == words.len() - 1;
+            if lightest < config.prune_freq || (last && node.data.freq <= config.prune_freq) {
+                return Explanation::BelowPruneFreq { word: word.clone(), freq: node.data.freq };
+            }
+            let len = word.chars().count();
+            if len < config.min_word_len {
+                return Explanation::BelowMinWordLen { word: word.clone(), len };
+            }
+            if len > config.max_word_len.unwrap_or(usize::MAX) {
+                return Explanation::AboveMaxWordLen { word: word.clone(), len };
+            }
+
+            for letter in word.chars() {
+                match constraint.step(&state, letter) {
+                    Some(next) => state = next,
+                    None => return Explanation::PatternMismatch { position },
+                }
+                position += 1;
+            }
+            word_freqs.push(node.data.freq);
+        }
+
+        let len = words.iter().map(|word| word.chars().count()).sum();
+        if len < config.min_length.unwrap_or(0) || len > config.max_length.unwrap_or(usize::MAX) {
+            return Explanation::WrongLength { len };
+        }
+        if !constraint.accept(&state) {
+            return Explanation::PatternUnfinished;
+        }
+
+        let score = config.scorer().score(&Candidate {
+            words: &words,
+            word_freqs: &word_freqs[..word_freqs.len() - 1],
+            best_freq: word_freqs[word_freqs.len() - 1],
+            max_freq: self.root().data.weight,
+            complete: true,
+        }, config);
+        let results = self.query_results_with(&SingleThreaded, constraint, config);
+        match results.iter().position(|result| result.words == words) {
+            Some(rank) => Explanation::Found { rank, score },
+            None => match config.max_results {
+                Some(max_results) if results.len() >= max_results =>
+                    Explanation::RankedPastMaxResults { score, max_results },
+                _ => Explanation::NotReached { score },
+            },
+        }
+    }
+}
+
+impl Display for Explanation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Explanation::Found { rank, score } => write!(f, "found as result {} (score {})", rank + 1, score),
+            Explanation::NotInList { word } => write!(f, "{} isn't in the wordlist", word),
+            Explanation::Banned { word } => write!(f, "{} is banned", word),
+            Explanation::MissingTags { word } => write!(f, "{} doesn't have the tags being searched for", word),
+            Explanation::BelowPruneFreq { word, freq } =>
+                write!(f, "{} (freq {}) is too rare for prune_freq", word, freq),
+            Explanation::TooManySpaces { spaces, allowed } =>
+                write!(f, "it has {} spaces, but only {} are allowed", spaces, allowed),
+            Explanation::BelowMinWordLen { word, len } =>
+                write!(f, "{} has {} letters, fewer than min_word_len", word, len),
+            Explanation::AboveMaxWordLen { word, len } =>
+                write!(f, "{} has {} letters, more than max_word_len", word, len),
+            Explanation::WrongLength { len } =>
+                write!(f, "it has {} letters, outside min_length to max_length", len),
+            Explanation::PatternMismatch { position } =>
+                write!(f, "the query doesn't match at position {}", position + 1),
+            Explanation::PatternUnfinished => write!(f, "the query wants more letters"),
+            Explanation::RankedPastMaxResults { score, max_results } =>
+                write!(f, "it matches (score {}), but isn't in the top {} results", score, max_results),
+            Explanation::NotReached { score } =>
+                write!(f, "it matches (score {}), but the search stopped before finding it", score),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::wordlist::trie::constraint::{Anagram, Regex};
+    use crate::wordlist::trie::explain::Explanation;
+    use crate::wordlist::trie::searchconfig::SearchConfig;
+    use crate::wordlist::trie::trie::Trie;
+
+    #[test]
+    fn explains_missing_words() {
+        let mut trie = Trie::default();
+        trie.add_with_freq("HELLO", 10);
+        trie.add_with_freq("HELP", 5);
+        trie.add_with_freq("GOOD", 20);
+        trie.add_with_freq("BYE", 1);
+        let trie = trie.build();
+        let regex = Regex::new("HEL(LO|P)");
+        let mut config = SearchConfig::new();
+
+        assert_eq!(trie.explain("HELLO", &regex, &config), Explanation::Found { rank: 0, score: 50 });
+        assert_eq!(trie.explain("HELM", &regex, &config), Explanation::NotInList { word: "HELM".into() });
+        assert_eq!(trie.explain("HEL", &regex, &config), Explanation::NotInList { word: "HEL".into() });
+        assert_eq!(trie.explain("GOOD", &regex, &config), Explanation::PatternMismatch { position: 0 });
+        assert_eq!(trie.explain("HELP BYE", &regex, &config), Explanation::TooManySpaces { spaces: 1, allowed: 0 });
+
+        config.max_results = Some(1);
+        assert_eq!(trie.explain("HELP", &regex, &config),
+                   Explanation::RankedPastMaxResults { score: 20, max_results: 1 });
+        config.prune_freq = 5;
+        assert_eq!(trie.explain("HELP", &regex, &config), Explanation::BelowPruneFreq { word: "HELP".into(), freq: 5 });
+        config.prune_freq = 0;
+        config.min_word_len = 5;
+        assert_eq!(trie.explain("HELP", &regex, &config), Explanation::BelowMinWordLen { word: "HELP".into(), len: 4 });
+
+        let mut config = SearchConfig::new();
+        config.space_penalty = Some(5);
+        config.spaces_allowed = 1;
+        let anagram = Anagram::new("DOGOBYE");
+        assert!(matches!(trie.explain("GOOD BYE", &anagram, &config), Explanation::Found { rank: 0, .. }));
+        assert_eq!(trie.explain("GOOD HELP", &anagram, &config), Explanation::PatternMismatch { position: 5 });
+        assert_eq!(trie.explain("GOOD", &anagram, &config), Explanation::PatternUnfinished);
+        assert_eq!(trie.explain("GOOD BYE", &Regex::new("GOODB_YE"), &config), Explanation::PatternMismatch { position: 4 });
+        config.max_length = Some(6);
+        assert_eq!(trie.explain("GOOD BYE", &anagram, &config), Explanation::WrongLength { len: 7 });
+    }
+}
@@ -2,12 +2,18 @@ pub mod trie;
 pub mod search;
 pub mod multithreaded_search;
 pub mod searchconfig;
+pub mod searchresult;
+pub mod scorer;
+pub mod constraint;
+pub mod executor;
+pub mod explain;
 
 mod haschildren;
 mod node;
 mod mutable_node_arena;
 mod iterators;
 mod concurrent_pq;
+mod update;
 
 
 
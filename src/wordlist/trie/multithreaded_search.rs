@@ -1,269 +1,488 @@
-use std::cmp::Ordering;
-use std::collections::{HashMap};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
 use std::fmt::Debug;
 
 use std::ops::{Deref};
 use std::sync::{Arc};
 use std::sync::Mutex;
-use std::sync::atomic::{AtomicBool};
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::time::Instant;
 
 use maplit::hashmap;
+use metrics::{counter, histogram};
 
 
-use rayon::{scope, Scope};
-
-use crate::regex::nfa::graph::NfaGraph;
-use crate::regex::nfa::state::NfaStateKind::Accept;
-use crate::regex::nfa::state::NfaStatePtr;
 use crate::wordlist::trie::concurrent_pq;
+use crate::wordlist::trie::constraint::{Anagram, Constraint, Regex};
 use crate::wordlist::trie::concurrent_pq::ConcurrentPQ;
+use crate::wordlist::trie::executor::{Executor, ThreadPool};
 
-use crate::wordlist::trie::node::{ImmutableTrieNode};
-use crate::wordlist::trie::searchconfig::SearchConfig;
+use crate::wordlist::trie::node::{ImmutableTrieNode, NO_WORDS, NodeRef};
+use crate::wordlist::trie::scorer::Candidate;
+use crate::wordlist::trie::searchconfig::{FrontierLimit, PageLimits, SearchConfig};
+use crate::wordlist::trie::searchresult::{SearchResult, SearchSummary};
 use crate::wordlist::trie::trie::{ImmutableTrie, Trie};
 
 
-trait StateT: Send + Debug {}
-
-pub trait ResultCallback: FnMut(String, &SearchConfig) -> bool + Sync + Send {}
+pub trait ResultCallback: FnMut(SearchResult, &SearchConfig) -> bool + Sync + Send {}
 
-impl<F: FnMut(String, &SearchConfig) -> bool + Sync + Send> ResultCallback for F {}
+impl<F: FnMut(SearchResult, &SearchConfig) -> bool + Sync + Send> ResultCallback for F {}
 
 //#[derive(Debug)]
-struct QItem<'q, State: StateT + 'q>(OrderedTrieNode<'q>, SearchState<'q>, State);
+struct QItem<'q, State: Send + 'q>(OrderedTrieNode<'q>, SearchState, State);
 
-impl<'q, State: StateT> concurrent_pq::QItem for QItem<'q, State> {}
+impl<'q, State: Send> concurrent_pq::QItem for QItem<'q, State> {}
 
-impl<State: StateT> PartialEq<Self> for QItem<'_, State> {
+impl<State: Send> PartialEq<Self> for QItem<'_, State> {
     fn eq(&self, other: &Self) -> bool {
         self.0.val == other.0.val
     }
 }
 
-impl<State: StateT> Eq for QItem<'_, State> {}
+impl<State: Send> Eq for QItem<'_, State> {}
 
-impl<State: StateT> PartialOrd<Self> for QItem<'_, State> {
+impl<State: Send> PartialOrd<Self> for QItem<'_, State> {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         Some(self.cmp(other))
     }
 }
 
-impl<State: StateT> Ord for QItem<'_, State> {
+impl<State: Send> Ord for QItem<'_, State> {
     fn cmp(&self, other: &Self) -> Ordering {
         self.0.cmp(&other.0)
     }
 }
 
-#[derive(Copy, Clone)]
-struct SearchParams<State: StateT> {
-    accept: for<'r> fn(&'r State) -> bool,
-    keep_going: for<'r> fn(&'r State, char) -> Option<State>,
-    score: for<'r> fn(&'r ImmutableTrieNode, &SearchState) -> isize,
+/// Asks a paged search for its next `size` results.
+pub(crate) struct PageRequest {
+    pub(crate) size: usize,
+    pub(crate) limits: PageLimits,
+}
+
+/// What a non-deterministic paged search keeps between pages.
+struct PageContext<'f, 'c, C: Constraint> {
+    pq: ConcurrentPQ<QItem<'f, C::State>>,
+    constraint: &'c C,
+    /// The search's config, with the current page's limits applied
+    config: SearchConfig,
+    progress: Arc<SearchProgress>,
+    /// Results found after the last page filled up
+    overflow: Vec<SearchResult>,
 }
 
-struct SearchState<'a> {
+struct SearchState {
     num_spaces: usize,
     current_word_len: usize,
-    prev_words: Vec<&'a ImmutableTrieNode<'a>>,
+    /// The words so far; the last one is the word being built
+    words: Vec<String>,
+    /// The frequencies of the words before the last one
+    word_freqs: Vec<usize>,
     total_len: usize,
-    prev_penalty: isize,
 }
 
-impl<'a> Clone for SearchState<'a> {
+impl Clone for SearchState {
     fn clone(&self) -> Self {
         SearchState {
             num_spaces: self.num_spaces,
             current_word_len: self.current_word_len,
-            prev_words: self.prev_words.clone(),
+            words: self.words.clone(),
+            word_freqs: self.word_freqs.clone(),
             total_len: self.total_len,
-            prev_penalty: self.prev_penalty,
         }
     }
 }
 
-impl<'a> SearchState<'a> {
-    fn new_word(&self, node: &'a ImmutableTrieNode<'a>, config: &SearchConfig) -> SearchState<'a> {
+impl SearchState {
+    fn new() -> SearchState {
+        SearchState {
+            num_spaces: 0,
+            current_word_len: 0,
+            words: vec![String::new()],
+            word_freqs: vec![],
+            total_len: 0,
+        }
+    }
+    fn new_word(&self, node: NodeRef) -> SearchState {
         let mut new = self.clone();
         new.num_spaces += 1;
         new.current_word_len = 0;
-        new.prev_words.push(node);
-        new.prev_penalty += config.space_penalty.unwrap() as isize - node.freq as isize;
+        new.words.push(String::new());
+        new.word_freqs.push(node.data.freq);
         new
     }
-    fn same_word(&self) -> SearchState<'a> {
+    fn same_word(&self, letter: char) -> SearchState {
         let mut new = self.clone();
+        new.words.last_mut().unwrap().push(letter);
         new.current_word_len += 1;
         new.total_len += 1;
         new
     }
+    /// Whether another word could be started after this one.
+    fn can_add_word(&self, config: &SearchConfig) -> bool {
+        self.num_spaces < config.spaces_allowed && config.space_penalty.is_some()
+            && self.total_len + config.min_word_len <= config.max_length.unwrap_or(usize::MAX)
+    }
+    /// Whether going on to `child` with another letter could still end in a word (and a result)
+    /// within the length limits, going by the depths of the words below it.
+    fn can_finish_at(&self, child: &ImmutableTrieNode, config: &SearchConfig) -> bool {
+        if child.min_depth == NO_WORDS {
+            return false;
+        }
+        let (word_len, total_len) = (self.current_word_len + 1, self.total_len + 1);
+        let (shortest, longest) = (child.min_depth as usize, child.max_depth as usize);
+        word_len + shortest <= config.max_word_len.unwrap_or(usize::MAX)
+            && total_len + shortest <= config.max_length.unwrap_or(usize::MAX)
+            && word_len + longest >= config.min_word_len
+            && (self.can_add_word(config) || total_len + longest >= config.min_length.unwrap_or(0))
+    }
+    /// Scores this state, heading towards `node`.
+    fn score(&self, node: NodeRef, trie: &ImmutableTrie, config: &SearchConfig) -> isize {
+        config.scorer().score(&Candidate {
+            words: &self.words,
+            word_freqs: &self.word_freqs,
+            best_freq: node.data.weight,
+            max_freq: trie.root().data.weight,
+            complete: false,
+        }, config)
+    }
+    /// Scores this state as a result, ending at `node`.
+    fn result_score(&self, node: NodeRef, trie: &ImmutableTrie, config: &SearchConfig) -> isize {
+        config.scorer().score(&Candidate {
+            words: &self.words,
+            word_freqs: &self.word_freqs,
+            best_freq: node.data.freq,
+            max_freq: trie.root().data.weight,
+            complete: true,
+        }, config)
+    }
 }
 
 
-impl<'a, 'scope> ImmutableTrie<'a> {
-    fn best_first_search<'f, State: StateT, F>
+impl ImmutableTrie {
+    fn best_first_search<'f, C: Constraint, F>
     (&'f self,
-     starting_state: State,
-     params: &SearchParams<State>,
+     executor: &dyn Executor,
+     constraint: &C,
      config: &'f SearchConfig,
-     result_callback: Arc<Mutex<F>>,
-    )
+     result_callback: F,
+    ) -> SearchSummary
         where F: ResultCallback
     {
-        let search_state = SearchState {
-            num_spaces: 0,
-            current_word_len: 0,
-            prev_words: vec![],
-            total_len: 0,
-            prev_penalty: 0,
-        };
-        let root = self.root.get().unwrap();
+        let root = self.root();
         let item = QItem(root.order(|_| 0),
-                         search_state, starting_state);
+                         SearchState::new(), constraint.start());
 
-        scope(|scope| {
-            let pq = ConcurrentPQ::<QItem<State>>::new();
-            let done = Arc::new(AtomicBool::new(false));
+        if config.deterministic {
+            self.ranked_search(item, constraint, config, result_callback);
+            return SearchSummary::default();
+        }
+        let pq = ConcurrentPQ::for_workers(executor.threads());
+        pq.push(item);
+        let progress = Arc::new(SearchProgress::new(constraint.kind()));
+        self.run_frontier(executor, &pq, constraint, config, &progress, result_callback);
+        progress.summary()
+    }
 
-            pq.push(item);
-            scope.spawn(move |s| {
-                Self::worker(
-                    pq, config, params, done, root, s, result_callback);
-            })
-        });
+    /// Runs workers on `executor` over what's left in `pq` until it runs out or the search stops.
+    /// Unless the callback stops the search, whatever isn't explored stays in `pq` to be run
+    /// again later.
+    fn run_frontier<'f, C: Constraint, F>(&'f self,
+                                          executor: &dyn Executor,
+                                          pq: &ConcurrentPQ<QItem<'f, C::State>>,
+                                          constraint: &C,
+                                          config: &SearchConfig,
+                                          progress: &Arc<SearchProgress>,
+                                          result_callback: F)
+        where F: ResultCallback
+    {
+        let result_callback = Mutex::new(result_callback);
+        pq.reopen();
+        executor.run(&|| self.worker(pq, constraint, config, progress, &result_callback));
+        progress.record(pq.take_retries());
     }
 
+    /// Runs a search a page at a time: for each request it sends back the next `size` results
+    /// and a summary of the search so far, keeping the frontier between pages so nothing gets
+    /// searched twice. `max_results` is ignored, and `max_expansions` is a budget for each page;
+    /// the search ends when `requests` hangs up.
+    ///
+    /// Deterministic searches finish ranking every result before handing out the best of them.
+    /// If a page's limits stop the ranking first, that page gets the best results found so far,
+    /// and the ranking carries on with the next page's limits.
+    fn paged_search<C: Constraint>(&self,
+                                   constraint: &C,
+                                   config: &SearchConfig,
+                                   requests: Receiver<PageRequest>,
+                                   pages: Sender<(Vec<SearchResult>, SearchSummary)>)
+    {
+        let mut start = Some(QItem(self.root().order(|_| 0), SearchState::new(), constraint.start()));
+        let mut context = PageContext {
+            pq: ConcurrentPQ::for_workers(ThreadPool.threads()),
+            constraint,
+            config: config.clone(),
+            progress: Arc::new(SearchProgress::new(constraint.kind())),
+            overflow: vec![],
+        };
+        // what's left to rank, and the ranked results not handed out yet
+        let mut unranked = vec![];
+        let mut ranked = BinaryHeap::new();
+
+        for request in requests {
+            context.config = request.limits.apply(config);
+            if let Some(item) = start.take() {
+                if config.deterministic {
+                    unranked.push(item);
+                } else {
+                    context.pq.push(item);
+                }
+            }
+            let page = if config.deterministic {
+                self.depth_first(&mut unranked, constraint, &context.config,
+                                 |result| ranked.push(Ranked(result)));
+                std::iter::from_fn(|| ranked.pop())
+                    .take(request.size)
+                    .map(|Ranked(result)| result)
+                    .collect()
+            } else {
+                self.fill_page(&ThreadPool, &mut context, request.size)
+            };
+            if pages.send((page, context.progress.summary())).is_err() {
+                return;
+            }
+        }
+    }
 
-    fn worker<'f, State: StateT, F>(mut pq: ConcurrentPQ<QItem<'f, State>>,
-                                    config: &'f SearchConfig,
-                                    params: &'f SearchParams<State>,
-                                    done: Arc<AtomicBool>,
-                                    root: &'f ImmutableTrieNode,
-                                    scope: &Scope<'scope>,
-                                    result_callback: Arc<Mutex<F>>,
-    )
-        where State: StateT + 'f, F: ResultCallback + 'scope,
-              'f: 'scope
+    /// Searches until it has `size` results (starting with any left over from the last page),
+    /// then pauses the workers. Results found by workers that were already running when the
+    /// page filled up are kept in the context's `overflow` for the next page.
+    fn fill_page<'f, C: Constraint>(&'f self,
+                                    executor: &dyn Executor,
+                                    context: &mut PageContext<'f, '_, C>,
+                                    size: usize) -> Vec<SearchResult>
     {
-        if config.max_results.is_some() && done.load(std::sync::atomic::Ordering::Relaxed) {
-            return;
+        let progress = &context.progress;
+        let page = Arc::new(Mutex::new(std::mem::take(&mut context.overflow)));
+        if page.lock().unwrap().len() < size {
+            progress.paused.store(false, std::sync::atomic::Ordering::Relaxed);
+            progress.expansions.store(0, std::sync::atomic::Ordering::Relaxed);
+            let callback = {
+                let page = page.clone();
+                let progress = progress.clone();
+                move |result, _: &SearchConfig| {
+                    let mut page = page.lock().unwrap();
+                    page.push(result);
+                    if page.len() >= size {
+                        progress.paused.store(true, std::sync::atomic::Ordering::Relaxed);
+                    }
+                    false
+                }
+            };
+            self.run_frontier(executor, &context.pq, context.constraint, &context.config, progress,
+                              callback);
         }
-        let item;
-        match pq.try_pop() {
-            Some(x) => item = x,
-            None => return
+        let mut page = std::mem::take(&mut *page.lock().unwrap());
+        if page.len() > size {
+            context.overflow = page.split_off(size);
         }
-        let QItem(node, search_state, state, ..) = item;
+        page
+    }
 
-        if node.is_terminal && node.freq > config.prune_freq {
-            if (params.accept)(&state) && search_state.current_word_len >= config.min_word_len {
-                let mut new_search_state = search_state.clone();
-                new_search_state.prev_words.push(node.node);
-                let result = new_search_state.prev_words.iter()
-                    .map(|x| x.path.clone()).collect::<Vec<_>>().join(" ");
-
-                let mut callback = result_callback.lock().unwrap();
-                if callback(result, config) {
-                    done.store(true, std::sync::atomic::Ordering::Relaxed);
-                    pq.clear();
-                    return;
-                }
+
+    /// Takes the best candidate from `pq` and expands it, over and over, until the search stops
+    /// or `pq` closes because there's nothing left to expand.
+    fn worker<'f, C: Constraint, F>(&'f self,
+                                    pq: &ConcurrentPQ<QItem<'f, C::State>>,
+                                    constraint: &C,
+                                    config: &SearchConfig,
+                                    progress: &SearchProgress,
+                                    result_callback: &Mutex<F>)
+        where F: ResultCallback
+    {
+        let emit = |mut result: SearchResult| {
+            result.discarded = progress.discarded.load(std::sync::atomic::Ordering::Relaxed);
+            let mut result_callback = result_callback.lock().unwrap();
+            // once the callback asks to stop, other workers' results are dropped
+            if progress.done.load(std::sync::atomic::Ordering::Relaxed) {
+                return true;
+            }
+            let stop = result_callback(result, config);
+            if stop {
+                progress.done.store(true, std::sync::atomic::Ordering::Relaxed);
+            }
+            stop
+        };
+        let push = |item| {
+            pq.push(item);
+            progress.peak_frontier.fetch_max(pq.len(), std::sync::atomic::Ordering::Relaxed);
+            if let Some(limit) = config.frontier_limit {
+                progress.limit_frontier(pq, limit);
+            }
+        };
+        while let Some(item) = pq.pop() {
+            if progress.should_stop(config) {
+                // out of budget; leave it for a later page
+                pq.push(item);
+                pq.done();
+                pq.close();
+                break;
+            }
+            progress.sample_frontier(pq.len());
+            let stopped = self.expand(item, constraint, config, progress, emit, push);
+            if stopped {
+                pq.clear();
+            }
+            pq.done();
+            if progress.stopped(config) {
+                pq.close();
+                break;
+            }
+        }
+    }
+
+    /// Explores every candidate on this thread, keeping the best `max_results` results, then
+    /// reports them best first. Equal scores are broken alphabetically, so the results are the
+    /// same on every run. If the search runs out of budget, the best results so far are reported.
+    fn ranked_search<'f, C: Constraint, F>(&'f self,
+                                           item: QItem<'f, C::State>,
+                                           constraint: &C,
+                                           config: &SearchConfig,
+                                           mut result_callback: F)
+        where F: ResultCallback
+    {
+        let limit = config.max_results.unwrap_or(usize::MAX);
+        let mut best = BinaryHeap::new();
+        self.depth_first(&mut vec![item], constraint, config, |result| {
+            best.push(Reverse(Ranked(result)));
+            if best.len() > limit {
+                best.pop();
+            }
+        });
+        for Reverse(Ranked(result)) in best.into_sorted_vec() {
+            if result_callback(result, config) {
+                return;
+            }
+        }
+    }
+
+    /// Explores `frontier` depth first on this thread, passing every result to `found`, until
+    /// it's empty or the search stops. Whatever isn't explored is left in `frontier`.
+    fn depth_first<'f, C: Constraint, F>(&'f self,
+                                         frontier: &mut Vec<QItem<'f, C::State>>,
+                                         constraint: &C,
+                                         config: &SearchConfig,
+                                         mut found: F)
+        where F: FnMut(SearchResult)
+    {
+        let progress = SearchProgress::new(constraint.kind());
+        while let Some(item) = frontier.pop() {
+            if progress.should_stop(config) {
+                frontier.push(item);
+                break;
+            }
+            progress.sample_frontier(frontier.len() + 1);
+            self.expand(item, constraint, config, &progress,
+                        |result| {
+                            found(result);
+                            false
+                        },
+                        |item| frontier.push(item));
+        }
+        progress.record(0);
+    }
+
+    /// Reports `item` to `emit` if it completes a result, then passes everything the search
+    /// could go on to from it to `push`. Returns `true` (without going on) if `emit` does.
+    fn expand<'f, C: Constraint, E, P>(&'f self,
+                                       item: QItem<'f, C::State>,
+                                       constraint: &C,
+                                       config: &SearchConfig,
+                                       progress: &SearchProgress,
+                                       mut emit: E,
+                                       mut push: P) -> bool
+        where E: FnMut(SearchResult) -> bool, P: FnMut(QItem<'f, C::State>)
+    {
+        let QItem(node, search_state, state, ..) = item;
+        progress.expanded.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+
+        if node.is_usable_word(config) && node.data.freq > config.prune_freq
+            && constraint.accept(&state) && search_state.current_word_len >= config.min_word_len
+            && search_state.total_len >= config.min_length.unwrap_or(0) {
+            let mut word_freqs = search_state.word_freqs.clone();
+            word_freqs.push(node.data.freq);
+            let result = SearchResult {
+                words: search_state.words.clone(),
+                word_freqs,
+                score: search_state.result_score(node.node, self, config),
+                source_list: None,
+                discarded: 0,
+            };
+
+            progress.found_result();
+            if emit(result) {
+                return true;
             }
         }
 
-        for child in node.node {
-            if (config.prune_freq > child.weight) {
+        let mut pruned = 0;
+        for (letter, child) in self.children(node.node) {
+            if config.prune_freq > child.data.weight {
+                pruned += 1;
                 continue
             }
-            if let Some(new_state) = (params.keep_going)(&state, child.letter) {
-                let pq = pq.clone();
-                let done = done.clone();
-                let new_search_state = search_state.same_word();
-                let item = QItem(
-                    child.order(|x|
-                        (params.score)(x, &search_state) - search_state.prev_penalty),
+            if !search_state.can_finish_at(&child, config) {
+                continue
+            }
+            if let Some(new_state) = constraint.step(&state, letter) {
+                let new_search_state = search_state.same_word(letter);
+                let heuristic = constraint.heuristic(&new_state);
+                push(QItem(
+                    child.order(|x| new_search_state.score(x, self, config) + heuristic),
                     new_search_state,
-                    new_state);
-                let result_callback = result_callback.clone();
-
-                pq.push(item);
-                scope.spawn(move |s| {
-                    Self::worker(pq, config, params, done, root, s, result_callback);
-                })
+                    new_state));
             }
         }
-        if search_state.num_spaces < config.spaces_allowed && search_state.current_word_len >= config.min_word_len {
-            if let Some(penalty) = config.space_penalty {
-                scope.spawn(move |s| {
-                    let mut ordered =
-                        root.order(|x| (params.score)(x, &search_state) - search_state.prev_penalty);
-                    ordered.val -= penalty as isize;
-                    let item = QItem(ordered,
-                                     search_state.new_word(node.node, config),
-                                     state);
-
-                    pq.push(item);
-                    Self::worker(pq, config, params, done.clone(), root, s, result_callback.clone());
-                })
+        if pruned > 0 {
+            progress.pruned.fetch_add(pruned, std::sync::atomic::Ordering::Relaxed);
+        }
+        // spaces are only allowed with a penalty to charge for them
+        if search_state.can_add_word(config) && search_state.current_word_len >= config.min_word_len
+            && node.is_usable_word(config) {
+            if let Some(new_state) = constraint.space(state) {
+                let new_search_state = search_state.new_word(node.node);
+                let heuristic = constraint.heuristic(&new_state);
+                push(QItem(self.root().order(|x| new_search_state.score(x, self, config) + heuristic),
+                           new_search_state,
+                           new_state));
             }
         }
+        false
     }
 
 
-    pub fn query_regex_multithreaded<F>(&self, regex: &str, config: &SearchConfig, result_callback: F)
-        where F: ResultCallback {
-        let nfa = NfaGraph::from_regex(regex);
-
-        let params = SearchParams::<(&NfaGraph, Vec<NfaStatePtr>)> {
-            keep_going: |state, c: char| {
-                let lstring = c.to_string();
-                let result = state.0.apply_with_start(&lstring, &state.1);
-                if result.states.is_empty() {
-                    None
-                } else {
-                    Some((state.0, result.states))
-                }
-            },
-            score: |x, search_state| (search_state.total_len as isize)  * (x.weight as isize),
-            accept: |state| state.1.iter().any(|x| x.kind_is(&Accept)),
-        };
-
-        self.best_first_search((&nfa, nfa.starting_states()),
-                               &params,
-                               config,
-                               Arc::new(Mutex::new(result_callback)));
+    /// Finds words (or phrases, if `config` allows spaces) that satisfy `constraint`, passing
+    /// each to `result_callback` until it returns `true`. The search runs on `executor`; apart
+    /// from the order results arrive in, every executor finds the same results.
+    pub fn query_with<E, C, F>(&self, executor: &E, constraint: &C, config: &SearchConfig,
+                               result_callback: F) -> SearchSummary
+        where E: Executor, C: Constraint, F: ResultCallback {
+        self.best_first_search(executor, constraint, config, result_callback)
     }
 
-    fn get_counts(word: &str) -> HashMap<char, usize> {
-        let mut counts = hashmap! {};
-
-        word.chars().for_each(|c| {
-            *counts.entry(c).or_insert(0) += 1;
-        });
-        counts
+    /// `query_with` on the rayon thread pool.
+    pub fn query_multithreaded<C, F>(&self, constraint: &C, config: &SearchConfig, result_callback: F) -> SearchSummary
+        where C: Constraint, F: ResultCallback {
+        self.query_with(&ThreadPool, constraint, config, result_callback)
     }
 
-    pub fn query_anagram_multithreaded<F>(&self, word: &str, config: &SearchConfig, result_callback: F)
-        where F: ResultCallback {
-        let params = SearchParams {
-            keep_going: |counts: &HashMap<char, usize>, c: char| {
-                if *counts.get(&c).unwrap_or(&0) > 0 {
-                    let mut new_counts = counts.clone();
-                    *new_counts.get_mut(&c).unwrap() -= 1;
-                    Some(new_counts)
-                } else { None }
-            },
-            score: |x, search_state|  (search_state.total_len as isize)  * (x.weight as isize),
-            accept: |counts: &HashMap<char, usize>| counts.values().all(|x| *x == 0),
-        };
-        self.best_first_search(
-            Self::get_counts(word),
-            &params,
-            config,
-            Arc::new(Mutex::new(result_callback)),
-        );
+    /// Like `query_multithreaded`, collecting up to `max_results` results.
+    pub fn query_results<C: Constraint>(&self, constraint: &C, config: &SearchConfig) -> Vec<SearchResult> {
+        self.query_results_with(&ThreadPool, constraint, config)
     }
 
-    pub fn query_anagram_results(&self, word: &str, config: &SearchConfig) -> Vec<String> {
+    /// Like `query_with`, collecting up to `max_results` results.
+    pub fn query_results_with<E, C>(&self, executor: &E, constraint: &C, config: &SearchConfig) -> Vec<SearchResult>
+        where E: Executor, C: Constraint {
         let results = Mutex::new(vec![]);
         let callback = |result, config: &SearchConfig| {
             let mut r = results.lock().unwrap();
@@ -273,37 +492,60 @@ impl<'a, 'scope> ImmutableTrie<'a> {
             r.push(result);
             false
         };
-        self.query_anagram_multithreaded(word, config, callback);
+        self.query_with(executor, constraint, config, callback);
         let x = results.lock().unwrap().clone();
         x
     }
 
-    pub fn query_regex_results(&self, word: &str, config: &SearchConfig) -> Vec<String> {
-        let results = Mutex::new(vec![]);
-        let callback = |result, config: &SearchConfig| {
-            let mut r = results.lock().unwrap();
-            if r.len() >= config.max_results.unwrap_or(usize::MAX) {
-                return true;
-            }
-            r.push(result);
-            false
-        };
-        self.query_regex_multithreaded(word, config, callback);
-        let x = results.lock().unwrap().clone();
-        x
+    /// Runs `paged_search` for `constraint`.
+    pub(crate) fn query_paged<C: Constraint>(&self, constraint: &C, config: &SearchConfig,
+                                             requests: Receiver<PageRequest>,
+                                             pages: Sender<(Vec<SearchResult>, SearchSummary)>) {
+        self.paged_search(constraint, config, requests, pages);
+    }
+
+    pub fn query_regex_multithreaded<F>(&self, regex: &str, config: &SearchConfig, result_callback: F) -> SearchSummary
+        where F: ResultCallback {
+        self.query_multithreaded(&Regex::new(regex), config, result_callback)
+    }
+
+    pub(crate) fn get_counts(word: &str) -> HashMap<char, usize> {
+        let mut counts = hashmap! {};
+
+        word.chars().for_each(|c| {
+            *counts.entry(c).or_insert(0) += 1;
+        });
+        counts
+    }
+
+    pub fn query_anagram_multithreaded<F>(&self, word: &str, config: &SearchConfig, result_callback: F) -> SearchSummary
+        where F: ResultCallback {
+        self.query_multithreaded(&Anagram::new(word), config, result_callback)
     }
-}
 
-impl StateT for HashMap<char, usize> {}
+    pub fn query_anagram_results(&self, word: &str, config: &SearchConfig) -> Vec<SearchResult> {
+        self.query_results(&Anagram::new(word), config)
+    }
 
-impl StateT for (&NfaGraph, Vec<NfaStatePtr>) {}
+    pub fn query_regex_results(&self, word: &str, config: &SearchConfig) -> Vec<SearchResult> {
+        self.query_results(&Regex::new(word), config)
+    }
+}
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(Debug)]
 struct OrderedTrieNode<'a> {
     val: isize,
-    node: &'a ImmutableTrieNode<'a>,
+    node: NodeRef<'a>,
 }
 
+impl PartialEq<Self> for OrderedTrieNode<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.val == other.val
+    }
+}
+
+impl Eq for OrderedTrieNode<'_> {}
+
 impl PartialOrd<Self> for OrderedTrieNode<'_> {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         Some(self.cmp(other))
@@ -316,15 +558,145 @@ impl Ord for OrderedTrieNode<'_> {
     }
 }
 
-impl ImmutableTrieNode<'_> {
-    fn order<'a, F>(&'a self, f: F) -> OrderedTrieNode<'a>
-        where F: Fn(&'a ImmutableTrieNode) -> isize {
+/// How often (in expansions) the size of the frontier is recorded.
+const FRONTIER_SAMPLE: usize = 256;
+
+/// Shared between the workers of one search.
+struct SearchProgress {
+    /// The kind of query, for stats
+    kind: &'static str,
+    started: Instant,
+    /// Set once the callback asks to stop
+    done: AtomicBool,
+    /// Set once a page of a paged search is full; unlike `done`, the frontier is kept
+    paused: AtomicBool,
+    expansions: AtomicUsize,
+    /// How many candidates have been added to the frontier
+    pushed: AtomicUsize,
+    /// How many candidates have been thrown away to stay within `frontier_limit`
+    discarded: AtomicUsize,
+    /// The largest the frontier has been
+    peak_frontier: AtomicUsize,
+    /// Stats not yet handed to `metrics`; see `record`
+    expanded: AtomicUsize,
+    pruned: AtomicUsize,
+    results: AtomicUsize,
+    found_any: AtomicBool,
+}
+
+impl SearchProgress {
+    /// Whether the search has been stopped; unlike `should_stop`, this doesn't count against
+    /// the budget.
+    fn stopped(&self, config: &SearchConfig) -> bool {
+        self.done.load(std::sync::atomic::Ordering::Relaxed)
+            || self.paused.load(std::sync::atomic::Ordering::Relaxed)
+            || config.out_of_budget(self.expansions.load(std::sync::atomic::Ordering::Relaxed))
+    }
+
+    fn new(kind: &'static str) -> SearchProgress {
+        SearchProgress {
+            kind,
+            started: Instant::now(),
+            done: Default::default(),
+            paused: Default::default(),
+            expansions: Default::default(),
+            pushed: Default::default(),
+            discarded: Default::default(),
+            peak_frontier: Default::default(),
+            expanded: Default::default(),
+            pruned: Default::default(),
+            results: Default::default(),
+            found_any: Default::default(),
+        }
+    }
+
+    /// The search's summary, as of now.
+    fn summary(&self) -> SearchSummary {
+        SearchSummary {
+            discarded: self.discarded.load(std::sync::atomic::Ordering::Relaxed),
+            peak_frontier: self.peak_frontier.load(std::sync::atomic::Ordering::Relaxed),
+        }
+    }
+
+    /// Notes that a result was found, recording how long the first one took.
+    fn found_result(&self) {
+        self.results.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        if !self.found_any.swap(true, std::sync::atomic::Ordering::Relaxed) {
+            histogram!("search_time_to_first_result", self.started.elapsed(), "query" => self.kind);
+        }
+    }
+
+    /// Records the size of the frontier every `FRONTIER_SAMPLE` expansions.
+    fn sample_frontier(&self, size: usize) {
+        if self.expansions.load(std::sync::atomic::Ordering::Relaxed).is_multiple_of(FRONTIER_SAMPLE) {
+            histogram!("search_frontier_size", size as f64, "query" => self.kind);
+        }
+    }
+
+    /// Hands the counts since the last call to `metrics`, along with `queue_retries`.
+    fn record(&self, queue_retries: usize) {
+        let take = |count: &AtomicUsize| count.swap(0, std::sync::atomic::Ordering::Relaxed) as u64;
+        counter!("search_nodes_expanded", take(&self.expanded), "query" => self.kind);
+        counter!("search_pruned_by_freq", take(&self.pruned), "query" => self.kind);
+        counter!("search_results", take(&self.results), "query" => self.kind);
+        counter!("search_queue_retries", queue_retries as u64, "query" => self.kind);
+    }
+
+    /// Whether the search should stop rather than expand another node.
+    fn should_stop(&self, config: &SearchConfig) -> bool {
+        self.done.load(std::sync::atomic::Ordering::Relaxed)
+            || self.paused.load(std::sync::atomic::Ordering::Relaxed)
+            || config.out_of_budget(self.expansions.fetch_add(1, std::sync::atomic::Ordering::Relaxed))
+    }
+
+    /// Cuts `pq` back to `limit` after every `limit.trim_interval()` candidates added to it.
+    fn limit_frontier<State: Send>(&self, pq: &ConcurrentPQ<QItem<State>>, limit: FrontierLimit) {
+        let pushed = self.pushed.fetch_add(1, std::sync::atomic::Ordering::Relaxed) + 1;
+        if !pushed.is_multiple_of(limit.trim_interval()) {
+            return;
+        }
+        let size = limit.size().max(1);
+        let discarded = match limit {
+            FrontierLimit::Total(_) => pq.retain_best(size, |_| 0),
+            FrontierLimit::PerDepth(_) => pq.retain_best(size, |item| item.1.total_len),
+        };
+        self.discarded.fetch_add(discarded, std::sync::atomic::Ordering::Relaxed);
+        counter!("search_frontier_discarded", discarded as u64, "query" => self.kind);
+    }
+}
+
+/// Orders results best first: by score, then alphabetically.
+#[derive(PartialEq, Eq)]
+struct Ranked(SearchResult);
+
+impl PartialOrd<Self> for Ranked {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Ranked {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.score.cmp(&other.0.score)
+            .then_with(|| other.0.words.cmp(&self.0.words))
+    }
+}
+
+impl<'a> NodeRef<'a> {
+    /// Whether this prefix is a word the search is allowed to use.
+    fn is_usable_word(&self, config: &SearchConfig) -> bool {
+        self.is_terminal && !self.data.suppressed
+            && config.tags.is_none_or(|tags| tags.matches(self.data.tags))
+    }
+
+    fn order<F>(self, f: F) -> OrderedTrieNode<'a>
+        where F: Fn(NodeRef<'a>) -> isize {
         OrderedTrieNode { val: f(self), node: self }
     }
 }
 
-impl<'a> From<&'a ImmutableTrieNode<'a>> for OrderedTrieNode<'a> {
-    fn from(node: &'a ImmutableTrieNode) ->
+impl<'a> From<NodeRef<'a>> for OrderedTrieNode<'a> {
+    fn from(node: NodeRef<'a>) ->
     Self {
         OrderedTrieNode::<'a> {
             val: 0,
@@ -334,20 +706,19 @@ impl<'a> From<&'a ImmutableTrieNode<'a>> for OrderedTrieNode<'a> {
 }
 
 impl<'a> Deref for OrderedTrieNode<'a> {
-    type Target = ImmutableTrieNode<'a>;
+    type Target = NodeRef<'a>;
 
-    fn deref(&self) -> &ImmutableTrieNode<'a> {
-        self.node
+    fn deref(&self) -> &NodeRef<'a> {
+        &self.node
     }
 }
 
 #[test]
 fn test_anagram_multithreaded() {
-    let words = vec!["HELLO", "HELP", "GOODBYE", "GOOD"];
-    let mut_trie = Trie::new();
-    mut_trie.add_all((&words).iter().map(|x| *x));
-    let trie = ImmutableTrie::new();
-    mut_trie.build(&trie);
+    let words = ["HELLO", "HELP", "GOODBYE", "GOOD"];
+    let mut mut_trie = Trie::default();
+    mut_trie.add_all(words.iter().copied());
+    let trie = mut_trie.build();
 
     let default_config = SearchConfig::new();
 
@@ -360,22 +731,21 @@ fn test_anagram_multithreaded() {
 
     assert_eq!(
         trie.query_anagram_results("LEHL", &default_config),
-        Vec::<String>::new());
+        Vec::<SearchResult>::new());
     assert_eq!(
         trie.query_anagram_results("LELO", &default_config),
-        Vec::<String>::new());
+        Vec::<SearchResult>::new());
     assert_eq!(
         trie.query_anagram_results("DOG", &default_config),
-        Vec::<String>::new());
+        Vec::<SearchResult>::new());
 }
 
 #[test]
 fn query_words_in_trie() {
-    let words = vec!["HELLO", "HELP", "GOODBYE", "GOOD"];
-    let mut_trie = Trie::new();
-    mut_trie.add_all((&words).iter().map(|x| *x));
-    let immut = ImmutableTrie::new();
-    mut_trie.build(&immut);
+    let words = ["HELLO", "HELP", "GOODBYE", "GOOD"];
+    let mut mut_trie = Trie::default();
+    mut_trie.add_all(words.iter().copied());
+    let immut = mut_trie.build();
     let default_config = SearchConfig::new();
 
     let mut result =
@@ -386,13 +756,66 @@ fn query_words_in_trie() {
 }
 
 
+#[test]
+fn deterministic_top_k() {
+    let mut mut_trie = Trie::default();
+    for (word, freq) in [("HELLO", 5), ("HELP", 9), ("HELM", 2), ("HELD", 9), ("HELIX", 7), ("HERO", 1)] {
+        mut_trie.add_with_freq(word, freq);
+    }
+    let trie = mut_trie.build();
+    let mut config = SearchConfig::new();
+    config.deterministic = true;
+
+    let all = trie.query_regex_results("HE(L|R)(LO|P|M|D|IX|O)", &config);
+    assert_eq!(all.len(), 6);
+    assert!(all.windows(2).all(|x| x[0].score >= x[1].score));
+    for _ in 0..5 {
+        assert_eq!(trie.query_regex_results("HE(L|R)(LO|P|M|D|IX|O)", &config), all);
+    }
+
+    config.max_results = Some(3);
+    assert_eq!(trie.query_regex_results("HE(L|R)(LO|P|M|D|IX|O)", &config), all[..3].to_vec());
+    assert_eq!(trie.query_anagram_results("PLEH", &config), vec!["HELP"]);
+}
+
+#[test]
+fn search_budgets() {
+    use std::time::Instant;
+    use crate::wordlist::trie::searchconfig::CancellationToken;
+
+    let words = ["HELLO", "HELP", "GOODBYE", "GOOD", "BYE"];
+    let mut mut_trie = Trie::default();
+    mut_trie.add_all(words.iter().copied());
+    let trie = mut_trie.build();
+
+    let mut config = SearchConfig::new();
+    config.cancel = Some(CancellationToken::new());
+    assert_eq!(trie.query_regex_results("HELP", &config).len(), 1);
+    config.cancel.as_ref().unwrap().cancel();
+    assert!(trie.query_regex_results("HELP", &config).is_empty());
+
+    let mut config = SearchConfig::new();
+    config.deadline = Some(Instant::now());
+    assert!(trie.query_regex_results("HELP", &config).is_empty());
+
+    // the root plus H, E, L and P
+    let mut config = SearchConfig::new();
+    config.max_expansions = Some(4);
+    assert!(trie.query_regex_results("HELP", &config).is_empty());
+    config.max_expansions = Some(5);
+    assert_eq!(trie.query_regex_results("HELP", &config), vec!["HELP"]);
+    config.deterministic = true;
+    assert_eq!(trie.query_regex_results("HELP", &config), vec!["HELP"]);
+    config.max_expansions = Some(4);
+    assert!(trie.query_regex_results("HELP", &config).is_empty());
+}
+
 #[test]
 fn query_words_in_trie_space_penalty() {
-    let words = vec!["HELLO", "HELP", "GOODBYE", "GOOD", "BYE"];
-    let mut_trie = Trie::new();
-    mut_trie.add_all((&words).iter().map(|x| *x));
-    let immut = ImmutableTrie::new();
-    mut_trie.build(&immut);
+    let words = ["HELLO", "HELP", "GOODBYE", "GOOD", "BYE"];
+    let mut mut_trie = Trie::default();
+    mut_trie.add_all(words.iter().copied());
+    let immut = mut_trie.build();
     let mut default_config = SearchConfig::new();
     default_config.space_penalty = Some(50);
     default_config.spaces_allowed = 3;
@@ -401,5 +824,142 @@ fn query_words_in_trie_space_penalty() {
         immut.query_regex_results("GOODBYE", &default_config);
     result.sort();
 
-    assert_eq!(result, vec!["GOOD BYE", "GOODBYE"])
+    assert_eq!(result, vec!["GOOD BYE", "GOODBYE"]);
+    assert_eq!(result[0].words, vec!["GOOD", "BYE"]);
+    assert_eq!(result[0].word_freqs, vec![1, 1]);
+}
+
+#[test]
+fn scorer_decides_order() {
+    use crate::wordlist::trie::scorer::by_name;
+
+    let mut mut_trie = Trie::default();
+    mut_trie.add_with_freq("HELLO", 10);
+    mut_trie.add_with_freq("HELP", 12);
+    let trie = mut_trie.build();
+    let mut config = SearchConfig::new();
+    config.deterministic = true;
+
+    // 5 letters * 10 beats 4 letters * 12
+    assert_eq!(trie.query_regex_results("HEL(LO|P)", &config), vec!["HELLO", "HELP"]);
+    config.scorer = by_name("freq");
+    assert_eq!(trie.query_regex_results("HEL(LO|P)", &config), vec!["HELP", "HELLO"]);
+    assert_eq!(trie.query_regex_results("HEL(LO|P)", &config)[0].score, 12);
+}
+
+#[test]
+fn length_limits() {
+    let words = ["HELLO", "HELP", "GOODBYE", "GOOD", "BYE"];
+    let mut mut_trie = Trie::default();
+    mut_trie.add_all(words.iter().copied());
+    let trie = mut_trie.build();
+    let mut config = SearchConfig::new();
+    config.space_penalty = Some(50);
+    config.spaces_allowed = 1;
+    let search = |config: &SearchConfig| {
+        let mut result = trie.query_regex_results("GOOD(BYE)?", config);
+        result.sort();
+        result
+    };
+
+    assert_eq!(search(&config), vec!["GOOD", "GOOD BYE", "GOODBYE"]);
+    config.max_length = Some(6);
+    assert_eq!(search(&config), vec!["GOOD"]);
+    config.max_length = None;
+    config.min_length = Some(5);
+    assert_eq!(search(&config), vec!["GOOD BYE", "GOODBYE"]);
+    config.min_length = None;
+    config.max_word_len = Some(4);
+    assert_eq!(search(&config), vec!["GOOD", "GOOD BYE"]);
+    config.max_word_len = None;
+    config.min_word_len = 4;
+    assert_eq!(search(&config), vec!["GOOD", "GOODBYE"]);
+    config.deterministic = true;
+    assert_eq!(search(&config), vec!["GOOD", "GOODBYE"]);
+    assert_eq!(trie.query_anagram_results("LEHPLOLEH", &config), vec!["HELLO HELP", "HELP HELLO"]);
+    config.max_length = Some(8);
+    assert_eq!(trie.query_anagram_results("LEHPLOLEH", &config), Vec::<SearchResult>::new());
+}
+
+#[test]
+fn frontier_limits() {
+    use crate::wordlist::trie::searchconfig::FrontierLimit;
+
+    let letters = "ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+    let mut mut_trie = Trie::default();
+    let words = letters.chars()
+        .flat_map(|a| letters.chars().flat_map(move |b| letters.chars().map(move |c| format!("{}{}{}", a, b, c))));
+    for (i, word) in words.enumerate() {
+        mut_trie.add_with_freq(&word, 1 + i * 7919 % 1000);
+    }
+    let trie = mut_trie.build();
+    // on one thread, candidates are explored in exactly best-first order
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+    let search = |config: &SearchConfig| pool.install(|| {
+        let mut found = vec![];
+        let summary = trie.query_multithreaded(&Regex::new("..."), config, |result, _| {
+            found.push(result);
+            false
+        });
+        (found, summary)
+    });
+
+    let mut config = SearchConfig::new();
+    let (all, summary) = search(&config);
+    assert_eq!(all.len(), 26 * 26 * 26);
+    assert!(all.iter().all(|x| x.discarded == 0));
+    assert_eq!(summary.discarded, 0);
+    assert!(summary.peak_frontier > 2000);
+
+    for limit in [FrontierLimit::Total(1), FrontierLimit::Total(1000), FrontierLimit::PerDepth(1), FrontierLimit::PerDepth(100)] {
+        config.frontier_limit = Some(limit);
+        let (found, summary) = search(&config);
+        // the best candidate is never the one thrown away
+        assert_eq!(found[0].words, all[0].words);
+        assert!(found.len() < all.len());
+        assert!(summary.discarded > 0);
+        // the discarded count on each result is the count so far
+        assert!(found.windows(2).all(|x| x[0].discarded <= x[1].discarded));
+        assert!(found.iter().all(|x| x.discarded <= summary.discarded));
+        // the root and up to three letters make four depths
+        let depths = match limit {
+            FrontierLimit::Total(_) => 1,
+            FrontierLimit::PerDepth(_) => 4,
+        };
+        assert!(summary.peak_frontier <= depths * (limit.size() + limit.trim_interval()),
+                "{:?} let the frontier reach {}", limit, summary.peak_frontier);
+    }
+}
+
+#[test]
+fn executors_agree() {
+    use crate::wordlist::trie::executor::SingleThreaded;
+
+    fn sorted<E: Executor, C: Constraint>(trie: &ImmutableTrie, executor: &E, constraint: &C,
+                                          config: &SearchConfig) -> Vec<SearchResult> {
+        let mut result = trie.query_results_with(executor, constraint, config);
+        result.sort();
+        result
+    }
+
+    let words = vec!["HELLO", "HELP", "GOODBYE", "GOOD", "BYE", "MAY", "TOON", "TOO", "MANY"];
+    let mut mut_trie = Trie::default();
+    mut_trie.add_all(words.iter().copied());
+    let trie = mut_trie.build();
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+    let mut config = SearchConfig::new();
+    config.space_penalty = Some(50);
+    config.spaces_allowed = 1;
+    config.min_length = Some(5);
+    config.max_word_len = Some(4);
+
+    let regex = Regex::new("(GOOD|HEL.)(BYE)?");
+    let single = sorted(&trie, &SingleThreaded, &regex, &config);
+    assert_eq!(single, vec!["GOOD BYE", "HELP BYE"]);
+    assert_eq!(single, pool.install(|| sorted(&trie, &ThreadPool, &regex, &config)));
+
+    let anagram = Anagram::new("TOOMANY");
+    let single = sorted(&trie, &SingleThreaded, &anagram, &config);
+    assert_eq!(single, vec!["MANY TOO", "MAY TOON", "TOO MANY", "TOON MAY"]);
+    assert_eq!(single, pool.install(|| sorted(&trie, &ThreadPool, &anagram, &config)));
 }
@@ -1,168 +1,247 @@
-use std::cell::{Cell};
+use std::cmp::{max, min};
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
 use std::fmt::{Debug, Formatter};
+use std::ops::Deref;
 
-
-
-
-use serde::{Deserialize, Serialize};
-use typed_arena::Arena;
 use crate::alphabet::{ALPHABET, get_idx};
 
-//
-// #[derive(Ord, PartialOrd, Eq, PartialEq, Default, Debug)]
-// pub struct TrieNodeRef<'a>(pub(crate) Cell<&'a MutableTrieNode>);
+/// Index of an `ImmutableTrieNode` in its trie's node list.
+pub(crate) type NodeId = u32;
 
-
-#[derive(Ord, PartialOrd, Eq, PartialEq, Serialize, Deserialize, Default)]
-pub(crate) struct TrieNode<'a> {
-    #[serde(skip)]
-    pub(crate) children: [Cell<Option<&'a TrieNode<'a>>>; ALPHABET.len()],
-    pub(crate) next_child: Cell<Option<[Option<usize>; ALPHABET.len()]>>,
+#[derive(Default)]
+pub(crate) struct TrieNode {
+    pub(crate) children: [Option<Box<TrieNode>>; ALPHABET.len()],
     pub(crate) letter: char,
-    pub(crate) is_terminal: Cell<bool>,
-    pub(crate) weight: Cell<usize>,
-    pub(crate) depth: usize,
-    pub(crate) freq: Cell<usize>,
-    pub(crate) path: String,
+    pub(crate) is_terminal: bool,
+    pub(crate) suppressed: bool,
+    pub(crate) tags: u64,
+    pub(crate) weight: usize,
+    pub(crate) freq: usize,
 }
 
-#[derive(Ord, PartialOrd, Eq, PartialEq, Default)]
-pub(crate) struct ImmutableTrieNode<'a> {
-    pub(crate) children: Vec<Option<&'a ImmutableTrieNode<'a>>>,
-    pub(crate) next_child: [Option<usize>; ALPHABET.len()],
-    pub(crate) letter: char,
+/// A node of the minimized automaton. Subtrees with the same shape (the same letters, ending
+/// words in the same places) are stored once, so a node can be reached by many different
+/// prefixes and doesn't know its own letter, depth or path; the letters live on the edges
+/// leading to it, and everything else about a prefix is in its `PrefixData`.
+#[derive(Ord, PartialOrd, Eq, PartialEq, Hash, Clone, Default)]
+pub(crate) struct ImmutableTrieNode {
+    /// Where this node's edges start in `ImmutableTrie::edges`
+    pub(crate) first_edge: u32,
+    pub(crate) num_edges: u8,
     pub(crate) is_terminal: bool,
+    /// How many prefixes this subtree holds, counting the one reaching this node
+    pub(crate) size: u32,
+    /// How many letters below this node the nearest word ends (0 if this node ends one), or
+    /// `NO_WORDS` if there's no word below it. Suppressed words count too.
+    pub(crate) min_depth: u16,
+    /// How many letters below this node the furthest word ends
+    pub(crate) max_depth: u16,
+}
+
+/// What's known about a prefix beyond the shape of the subtree it leads to.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub(crate) struct PrefixData {
+    pub(crate) suppressed: bool,
+    pub(crate) tags: u64,
+    /// The highest unsuppressed frequency of any word starting with this prefix
     pub(crate) weight: usize,
-    pub(crate) depth: usize,
     pub(crate) freq: usize,
-    pub(crate) path: String,
 }
-impl TrieNode<'_> {
-    pub(crate) fn make_immutable<'a>(&self, arena: &'a Arena<ImmutableTrieNode<'a>>) -> &'a ImmutableTrieNode<'a> {
-        let mut children = Vec::with_capacity(ALPHABET.len());
-        self.children.iter().for_each(
-            |x| children.push(x.get().map(|child| child.make_immutable(arena)))
-        );
-        self.build_next_child();
-        arena.alloc(ImmutableTrieNode {
-            children,
-            next_child: self.next_child.get().unwrap(),
-            letter: self.letter,
-            is_terminal: self.is_terminal.get(),
-            weight: self.weight.get(),
-            freq: self.freq.get(),
-            depth: self.depth,
-            path: self.path.clone(),
-        })
+
+/// A node as reached by one particular prefix, along with that prefix's data.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct NodeRef<'a> {
+    pub(crate) node: &'a ImmutableTrieNode,
+    /// The prefix's number; prefixes are numbered in preorder, starting with 0 at the root
+    pub(crate) prefix: u32,
+    pub(crate) data: &'a PrefixData,
+}
+
+impl Deref for NodeRef<'_> {
+    type Target = ImmutableTrieNode;
+
+    fn deref(&self) -> &ImmutableTrieNode {
+        self.node
+    }
+}
+
+/// `min_depth` of a node with no words below it.
+pub(crate) const NO_WORDS: u16 = u16::MAX;
+
+#[derive(Ord, PartialOrd, Eq, PartialEq, Hash, Clone, Copy, Debug)]
+pub(crate) struct Edge {
+    pub(crate) letter: char,
+    pub(crate) node: NodeId,
+    /// What to add to the parent's prefix number to get the child's
+    pub(crate) offset: u32,
+}
+
+/// Collects nodes for an `ImmutableTrie`, reusing an existing node whenever an identical one
+/// (same terminal flag and edges) has already been added.
+#[derive(Default)]
+pub(crate) struct Minimizer {
+    pub(crate) nodes: Vec<ImmutableTrieNode>,
+    pub(crate) edges: Vec<Edge>,
+    registry: HashMap<(ImmutableTrieNode, Vec<Edge>), NodeId>,
+}
+
+impl Minimizer {
+    pub(crate) fn add(&mut self, node: ImmutableTrieNode, edges: Vec<Edge>) -> NodeId {
+        match self.registry.entry((node, edges)) {
+            Entry::Occupied(entry) => *entry.get(),
+            Entry::Vacant(entry) => {
+                let (node, edges) = entry.key();
+                let id = push_node(&mut self.nodes, &mut self.edges, node.clone(), edges);
+                *entry.insert(id)
+            }
+        }
     }
+
+    /// Adds every node of `other`, which was minimized on its own, and returns the id each of
+    /// them has here.
+    pub(crate) fn absorb(&mut self, other: &Minimizer) -> Vec<NodeId> {
+        let mut ids: Vec<NodeId> = Vec::with_capacity(other.nodes.len());
+        // children come before their parents, so their new ids are always known
+        for node in &other.nodes {
+            let edges = other.edges[node.edge_range()].iter()
+                .map(|edge| Edge { node: ids[edge.node as usize], ..*edge })
+                .collect();
+            ids.push(self.add(ImmutableTrieNode { first_edge: 0, num_edges: 0, ..node.clone() }, edges));
+        }
+        ids
+    }
+}
+
+/// Appends `node` with its `edges` and returns its id.
+pub(crate) fn push_node(nodes: &mut Vec<ImmutableTrieNode>, all_edges: &mut Vec<Edge>,
+                        mut node: ImmutableTrieNode, edges: &[Edge]) -> NodeId {
+    node.first_edge = all_edges.len() as u32;
+    node.num_edges = edges.len() as u8;
+    all_edges.extend_from_slice(edges);
+    nodes.push(node);
+    (nodes.len() - 1) as NodeId
 }
 
-impl<'n> TrieNode<'n> {
-    pub(crate) fn map_child<T, F>(&self, f: &mut F) -> Vec<T>
-        where F: FnMut(&TrieNode) -> T {
-        self.children.iter()
-            .map(|x| {
-                let optx = x.get();
-                optx.map(|x| f(x))
-            })
-            .filter(|x| x.is_some())
-            .map(|x| x.unwrap())
-            .collect()
+impl TrieNode {
+    /// Adds this subtree to `minimizer` (children before parents) and returns the id of this node.
+    pub(crate) fn make_immutable(&self, minimizer: &mut Minimizer) -> NodeId {
+        let mut edges = self.children.iter()
+            .flatten()
+            .map(|child| Edge { letter: child.letter, node: child.make_immutable(minimizer), offset: 0 })
+            .collect::<Vec<_>>();
+        let mut node = ImmutableTrieNode { is_terminal: self.is_terminal, ..Default::default() };
+        node.connect(&mut edges, &minimizer.nodes);
+        minimizer.add(node, edges)
+    }
+
+    /// The data of the prefix ending at this node.
+    pub(crate) fn prefix_data(&self) -> PrefixData {
+        PrefixData {
+            suppressed: self.suppressed,
+            tags: self.tags,
+            weight: self.weight,
+            freq: self.freq,
+        }
+    }
+
+    /// Appends the data of every prefix in this subtree to `prefixes`, in preorder.
+    pub(crate) fn collect_prefixes(&self, prefixes: &mut Vec<PrefixData>) {
+        prefixes.push(self.prefix_data());
+        for child in self.children.iter().flatten() {
+            child.collect_prefixes(prefixes);
+        }
     }
 
-    pub(crate) fn decorate(&self) -> &TrieNode<'n> {
-        for child_cell in &self.children {
-            child_cell.set(
-                match child_cell.get() {
-                    None => None,
-                    Some(child) => Some(child.decorate())
-                })
+    pub(crate) fn decorate(&mut self) {
+        let mut weight = self.unsuppressed_freq();
+        for child in self.children.iter_mut().flatten() {
+            child.decorate();
+            weight = max(weight, child.weight);
         }
+        self.weight = weight;
+    }
 
-        self.weight.set(self.map_child(&mut |x| x.weight.get())
-            .iter()
-            .fold(self.freq.get(), |x, y| x + y));
-        self
+    /// The frequency this node contributes to subtree weights; suppressed words count for nothing.
+    pub(crate) fn unsuppressed_freq(&self) -> usize {
+        if self.suppressed { 0 } else { self.freq }
+    }
+
+    /// The node reached by following `letters` down from this one, creating it if needed.
+    pub(crate) fn get_or_create_path<I>(&mut self, letters: I) -> &mut TrieNode
+        where I: IntoIterator<Item=char> {
+        let mut current = self;
+        for c in letters {
+            current = current.get_or_create_child(c);
+        }
+        current
+    }
+
+    pub(crate) fn get_or_create_child(&mut self, c: char) -> &mut TrieNode {
+        self.children[get_idx(c)].get_or_insert_with(|| Box::new(TrieNode {
+            letter: c,
+            ..Default::default()
+        }))
     }
 }
 
-impl Debug for TrieNode<'_> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("MutableTrieNode")
-            .field("path", &self.path)
-            .field("letter", &self.letter)
-            .field("weight", &self.weight)
-            .field("freq", &self.freq)
-            .field("is_terminal", &self.is_terminal)
-            .field("children", &self.children.iter()
-                .filter(|x| x.get().is_some())
-                .map(|x| x.get().unwrap().letter)
-                .collect::<Vec<_>>(),
-            )
-            .finish()
+impl PrefixData {
+    /// See `TrieNode::unsuppressed_freq`.
+    pub(crate) fn unsuppressed_freq(&self) -> usize {
+        if self.suppressed { 0 } else { self.freq }
     }
 }
 
+impl ImmutableTrieNode {
+    /// Works out `size`, `min_depth` and `max_depth` from whether this node ends a word and its
+    /// children (looked up in `nodes`), and sets the offsets on its `edges` to match.
+    pub(crate) fn connect(&mut self, edges: &mut [Edge], nodes: &[ImmutableTrieNode]) {
+        let (mut min_depth, mut max_depth) = if self.is_terminal { (0, 0) } else { (NO_WORDS, 0) };
+        let mut size = 1;
+        for edge in edges {
+            let child = &nodes[edge.node as usize];
+            edge.offset = size;
+            size += child.size;
+            if child.min_depth != NO_WORDS {
+                min_depth = min(min_depth, child.min_depth + 1);
+                max_depth = max(max_depth, child.max_depth.saturating_add(1));
+            }
+        }
+        self.size = size;
+        self.min_depth = min_depth;
+        self.max_depth = max_depth;
+    }
 
-impl Debug for ImmutableTrieNode<'_> {
+    pub(crate) fn edge_range(&self) -> std::ops::Range<usize> {
+        self.first_edge as usize..self.first_edge as usize + self.num_edges as usize
+    }
+}
+
+impl Debug for TrieNode {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("MutableTrieNode")
-            .field("path", &self.path)
             .field("letter", &self.letter)
             .field("weight", &self.weight)
             .field("freq", &self.freq)
             .field("is_terminal", &self.is_terminal)
+            .field("suppressed", &self.suppressed)
             .field("children", &self.children.iter()
-                .filter(|x| x.is_some())
-                .map(|x| x.as_ref().unwrap().letter)
+                .flatten()
+                .map(|x| x.letter)
                 .collect::<Vec<_>>(),
             )
             .finish()
     }
 }
 
-impl<'a> TrieNode<'a> {
-    fn create_child<'f>(&'f self, c: char,
-                        arena: &'a Arena<TrieNode<'a>>) {
-        let mut path = self.path.clone();
-        path.push(c);
-        self.children[get_idx(c)].set(
-            Some(
-                arena.alloc(TrieNode {
-                    weight: Cell::new(0),
-                    next_child: Default::default(),
-                    children: Default::default(),
-                    letter: c,
-                    is_terminal: Cell::new(false),
-                    freq: Cell::new(0),
-                    depth: self.depth + 1,
-                    path,
-                })));
-    }
 
-    pub(crate) fn get_or_create_child<'f>(&'f self, c: char,
-                                          arena: &'a Arena<TrieNode<'a>>)
-    //path_arena: &'f Arena<String>)
-                                          -> &'f Cell<Option<&'a TrieNode<'a>>> {
-        if self.get_child(c).is_none() {
-            self.create_child(c, arena);
-        }
-        return &self.children[get_idx(c)];
-    }
-}
-
-impl<'a> TrieNode<'a> {
-    pub(crate) fn build_next_child(&self) {
-        let mut next_child = [None; ALPHABET.len()];
-        let mut idx: isize = (next_child.len() - 1) as isize;
-        let mut next_idx = None;
-        while idx >= 0 {
-            next_child[idx as usize] = next_idx.clone();
-            if self.children[idx as usize].get().is_some() {
-                next_idx = Some(idx as usize)
-            }
-            idx -= 1;
-        }
-        self.next_child.set(Some(next_child));
+impl Debug for ImmutableTrieNode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ImmutableTrieNode")
+            .field("is_terminal", &self.is_terminal)
+            .field("size", &self.size)
+            .field("depths", &(self.min_depth..=self.max_depth))
+            .field("edges", &self.edge_range())
+            .finish()
     }
 }
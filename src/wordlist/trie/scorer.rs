@@ -0,0 +1,148 @@
+use std::sync::Arc;
+
+use crate::wordlist::trie::searchconfig::SearchConfig;
+
+/// What a `Scorer` gets to see about a partial (or finished) result.
+pub struct Candidate<'a> {
+    /// The words so far; unless `complete`, the last one is still being built (and may be empty)
+    pub words: &'a [String],
+    /// Frequencies of the finished words, i.e. all but the last
+    pub word_freqs: &'a [usize],
+    /// The highest frequency of any word the last one could still turn into
+    pub best_freq: usize,
+    /// The highest frequency of any word in the wordlist
+    pub max_freq: usize,
+    /// Whether the last word is finished, i.e. this is a result (and `best_freq` is the last
+    /// word's own frequency)
+    pub complete: bool,
+}
+
+impl Candidate<'_> {
+    fn letters(&self) -> usize {
+        self.words.iter().map(|word| word.chars().count()).sum()
+    }
+
+    /// The frequencies of the finished words, then `best_freq` for the last one.
+    fn freqs(&self) -> impl Iterator<Item=usize> + '_ {
+        self.word_freqs.iter().copied().chain(Some(self.best_freq))
+    }
+
+    /// Sum of the words' log frequencies relative to `max_freq` (so never positive).
+    fn log_prob(&self) -> f64 {
+        let max = self.max_freq.max(1) as f64;
+        self.freqs().map(|freq| (freq.max(1) as f64 / max).ln()).sum()
+    }
+
+    /// `space_penalty` for every word after the first.
+    fn space_penalties(&self, config: &SearchConfig) -> isize {
+        (config.space_penalty.unwrap_or(0) * (self.words.len() - 1)) as isize
+    }
+}
+
+/// Decides the order a best-first search looks at candidates in; higher goes first. Results are
+/// scored again once complete, which gives them their final score.
+pub trait Scorer: Send + Sync {
+    fn score(&self, candidate: &Candidate, config: &SearchConfig) -> isize;
+}
+
+/// Log probabilities are scaled up by this much to fit in a score.
+pub(crate) const LOG_SCALE: f64 = 1000.0;
+
+/// Letters times `best_freq`, less `space_penalty` for each extra word, but giving back the
+/// frequencies of the finished words. The default.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct LengthWeighted;
+
+impl Scorer for LengthWeighted {
+    fn score(&self, candidate: &Candidate, config: &SearchConfig) -> isize {
+        let finished: usize = candidate.word_freqs.iter().sum();
+        (candidate.letters() * candidate.best_freq) as isize + finished as isize
+            - candidate.space_penalties(config)
+    }
+}
+
+/// The frequency of the rarest word, less `space_penalty` for each extra word.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct RawFrequency;
+
+impl Scorer for RawFrequency {
+    fn score(&self, candidate: &Candidate, config: &SearchConfig) -> isize {
+        candidate.freqs().min().unwrap_or(0) as isize - candidate.space_penalties(config)
+    }
+}
+
+/// The sum of the words' log probabilities, so every extra word costs something.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct LogProbability;
+
+impl Scorer for LogProbability {
+    fn score(&self, candidate: &Candidate, _: &SearchConfig) -> isize {
+        (LOG_SCALE * candidate.log_prob()) as isize
+    }
+}
+
+/// The log probability per letter, so long phrases aren't punished for being long.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct LengthNormalized;
+
+impl Scorer for LengthNormalized {
+    fn score(&self, candidate: &Candidate, _: &SearchConfig) -> isize {
+        (LOG_SCALE * candidate.log_prob() / candidate.letters().max(1) as f64) as isize
+    }
+}
+
+/// The geometric mean of the words' frequencies.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct GeometricMean;
+
+impl Scorer for GeometricMean {
+    fn score(&self, candidate: &Candidate, _: &SearchConfig) -> isize {
+        let (count, log_sum) = candidate.freqs()
+            .fold((0, 0.0), |(count, sum), freq| (count + 1, sum + (freq.max(1) as f64).ln()));
+        (log_sum / count as f64).exp().round() as isize
+    }
+}
+
+/// Names the built-in scorers go by in `by_name`.
+pub const SCORER_NAMES: [&str; 5] = ["weighted", "freq", "logprob", "normalized", "geomean"];
+
+/// A built-in scorer by name (one of `SCORER_NAMES`).
+pub fn by_name(name: &str) -> Option<Arc<dyn Scorer>> {
+    match name {
+        "weighted" => Some(Arc::new(LengthWeighted)),
+        "freq" => Some(Arc::new(RawFrequency)),
+        "logprob" => Some(Arc::new(LogProbability)),
+        "normalized" => Some(Arc::new(LengthNormalized)),
+        "geomean" => Some(Arc::new(GeometricMean)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::wordlist::trie::scorer::{by_name, Candidate, SCORER_NAMES};
+    use crate::wordlist::trie::searchconfig::SearchConfig;
+
+    #[test]
+    fn common_words_beat_rare_ones() {
+        let words = ["GOOD".to_string(), "BYE".to_string()];
+        let common = Candidate { words: &words, word_freqs: &[100], best_freq: 50, max_freq: 100, complete: true };
+        let rare = Candidate { words: &words, word_freqs: &[2], best_freq: 5, max_freq: 100, complete: true };
+        let config = SearchConfig::new();
+        for name in SCORER_NAMES {
+            let scorer = by_name(name).unwrap();
+            assert!(scorer.score(&common, &config) > scorer.score(&rare, &config), "{}", name);
+        }
+        assert!(by_name("nonsense").is_none());
+    }
+
+    #[test]
+    fn log_probability_charges_for_extra_words() {
+        let one = ["GOODBYE".to_string()];
+        let two = ["GOOD".to_string(), "BYE".to_string()];
+        let config = SearchConfig::new();
+        let scorer = by_name("logprob").unwrap();
+        assert!(scorer.score(&Candidate { words: &one, word_freqs: &[], best_freq: 50, max_freq: 100, complete: true }, &config)
+            > scorer.score(&Candidate { words: &two, word_freqs: &[50], best_freq: 50, max_freq: 100, complete: true }, &config));
+    }
+}
@@ -1,15 +1,69 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
 
+use crate::wordlist::trie::scorer::{LengthWeighted, Scorer};
 
-
-
-#[derive(Default)]
+#[derive(Default, Clone)]
 pub struct SearchConfig {
+    /// Only return words carrying all of these tags; get one from `Wordlist::tag_filter`.
+    pub tags: Option<TagFilter>,
     pub max_results: Option<usize>,
+    /// Limits on the total number of letters in a result (spaces don't count)
+    pub min_length: Option<usize>,
     pub max_length: Option<usize>,
     pub space_penalty: Option<usize>,
     pub spaces_allowed: usize,
+    /// Limits on the length of each word in a result
     pub min_word_len: usize,
+    pub max_word_len: Option<usize>,
     pub prune_freq: usize,
+    /// Return results in exact score order (best first, ties broken alphabetically), the same
+    /// on every run. The search then runs on one thread and has to look at every candidate
+    /// before it can return anything.
+    pub deterministic: bool,
+    /// Stop searching (returning the results found so far) once this passes
+    pub deadline: Option<Instant>,
+    /// Stop searching after looking at this many trie nodes
+    pub max_expansions: Option<usize>,
+    /// Stop searching once this is cancelled
+    pub cancel: Option<CancellationToken>,
+    /// How candidates are ranked; `LengthWeighted` if not set. See `scorer::by_name` for the
+    /// built-in ones.
+    pub scorer: Option<Arc<dyn Scorer>>,
+    /// Bounds the memory a search uses by throwing away its worst candidates, at the cost of
+    /// possibly missing results; see `SearchResult::discarded`. Deterministic searches go
+    /// depth first, which doesn't need the memory, and ignore this.
+    pub frontier_limit: Option<FrontierLimit>,
+}
+
+/// The fewest candidates added to the frontier between cutting it back.
+const MIN_TRIM_INTERVAL: usize = 64;
+
+/// How many candidates a search keeps waiting to be explored. Cutting the frontier back means
+/// sorting all of it, so rather than on every push it's done every `trim_interval` candidates
+/// added. In between it can grow past the limit by up to that many (at any one depth, for
+/// `PerDepth`), e.g. to 1250 candidates for a limit of 1000, or 65 for a limit of 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrontierLimit {
+    /// At most this many candidates in all
+    Total(usize),
+    /// At most this many candidates with any one number of letters so far (a beam search)
+    PerDepth(usize),
+}
+
+impl FrontierLimit {
+    pub fn size(&self) -> usize {
+        match self {
+            FrontierLimit::Total(size) | FrontierLimit::PerDepth(size) => *size,
+        }
+    }
+
+    /// How many candidates are added to the frontier between cutting it back: a quarter of the
+    /// limit, so the cost of sorting the frontier is spread over them, but at least 64.
+    pub fn trim_interval(&self) -> usize {
+        (self.size() / 4).max(MIN_TRIM_INTERVAL)
+    }
 }
 
 
@@ -17,12 +71,95 @@ pub struct SearchConfig {
 impl SearchConfig {
     pub fn new() -> SearchConfig {
         SearchConfig {
+            tags: None,
             max_results: None,
+            min_length: None,
             max_length: None,
             space_penalty: None,
             spaces_allowed: 0,
             min_word_len: 3,
-            prune_freq: 0
+            max_word_len: None,
+            prune_freq: 0,
+            deterministic: false,
+            deadline: None,
+            max_expansions: None,
+            cancel: None,
+            scorer: None,
+            frontier_limit: None,
         }
     }
-}
\ No newline at end of file
+
+    pub(crate) fn scorer(&self) -> &dyn Scorer {
+        self.scorer.as_deref().unwrap_or(&LengthWeighted)
+    }
+
+    /// Whether a search that has looked at `expansions` nodes should give up.
+    pub(crate) fn out_of_budget(&self, expansions: usize) -> bool {
+        self.cancel.as_ref().is_some_and(|x| x.is_cancelled())
+            || self.max_expansions.is_some_and(|max| expansions >= max)
+            || self.deadline.is_some_and(|deadline| Instant::now() >= deadline)
+    }
+}
+
+/// Lets another thread (e.g. a Ctrl-C handler) stop a search. Clones share the same state.
+#[derive(Default, Clone, Debug)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+    parent: Option<Box<CancellationToken>>,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        Default::default()
+    }
+
+    /// A token that's cancelled whenever this one is, but can also be cancelled on its own.
+    pub fn child(&self) -> CancellationToken {
+        CancellationToken { cancelled: Default::default(), parent: Some(Box::new(self.clone())) }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed) || self.parent.as_ref().is_some_and(|parent| parent.is_cancelled())
+    }
+}
+
+/// Budget for fetching one page of a paged search, replacing the search's own `cancel` and
+/// `deadline` for that page.
+#[derive(Default, Clone, Debug)]
+pub struct PageLimits {
+    pub cancel: Option<CancellationToken>,
+    pub deadline: Option<Instant>,
+}
+
+impl PageLimits {
+    pub(crate) fn apply(&self, config: &SearchConfig) -> SearchConfig {
+        let mut config = config.clone();
+        if self.cancel.is_some() {
+            config.cancel = self.cancel.clone();
+        }
+        if self.deadline.is_some() {
+            config.deadline = self.deadline;
+        }
+        config
+    }
+}
+
+/// A set of tags, stored as a bitmask over the tags a wordlist knows about.
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TagFilter {
+    mask: u64,
+}
+
+impl TagFilter {
+    pub(crate) fn new(mask: u64) -> TagFilter {
+        TagFilter { mask }
+    }
+
+    pub(crate) fn matches(&self, tags: u64) -> bool {
+        tags & self.mask == self.mask
+    }
+}
@@ -0,0 +1,82 @@
+use std::fmt::{Display, Formatter};
+
+use serde::{Deserialize, Serialize};
+
+/// A single result of a search: one or more words and how they scored.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct SearchResult {
+    pub words: Vec<String>,
+    /// The frequency of each of `words` in the wordlist
+    pub word_freqs: Vec<usize>,
+    /// The priority the search gave this result; higher is better. Only comparable between
+    /// results of the same search.
+    pub score: isize,
+    /// The name of the wordlist the words came from, if it has one
+    pub source_list: Option<String>,
+    /// How many candidates the search had thrown away to stay within its `frontier_limit` by
+    /// the time it found this. If it's not 0, better results than this may have been missed.
+    /// It's a running count, so a result found before the frontier was first cut back says 0
+    /// even if the search went on to discard candidates; see `SearchSummary` for the total.
+    #[serde(default)]
+    pub discarded: usize,
+}
+
+/// What a search reports about itself once it's over.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SearchSummary {
+    /// How many candidates the search threw away in all to stay within its `frontier_limit`.
+    /// If it's not 0, some results may have been missed.
+    pub discarded: usize,
+    /// The most candidates the search had waiting to be explored at once
+    #[serde(default)]
+    pub peak_frontier: usize,
+}
+
+impl SearchResult {
+    /// The words joined by spaces.
+    pub fn text(&self) -> String {
+        self.words.join(" ")
+    }
+}
+
+impl Display for SearchResult {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.text())
+    }
+}
+
+impl PartialEq<str> for SearchResult {
+    fn eq(&self, other: &str) -> bool {
+        self.words.len() == other.split(' ').count()
+            && self.words.iter().zip(other.split(' ')).all(|(x, y)| x == y)
+    }
+}
+
+impl PartialEq<&str> for SearchResult {
+    fn eq(&self, other: &&str) -> bool {
+        self == *other
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::wordlist::trie::searchresult::SearchResult;
+
+    #[test]
+    fn compares_with_text() {
+        let result = SearchResult {
+            words: vec!["GOOD".to_string(), "BYE".to_string()],
+            word_freqs: vec![10, 4],
+            score: 3,
+            source_list: None,
+            discarded: 0,
+        };
+        assert_eq!(result, "GOOD BYE");
+        assert_ne!(result, "GOODBYE");
+        assert_ne!(result, "GOOD");
+        assert_eq!(result.to_string(), "GOOD BYE");
+        assert_eq!(serde_json::to_string(&result).unwrap(),
+                   r#"{"words":["GOOD","BYE"],"word_freqs":[10,4],"score":3,"source_list":null,"discarded":0}"#);
+    }
+}
@@ -1,106 +1,249 @@
+use std::cmp::max;
+use std::fmt::{Debug, Display, Formatter};
+use std::mem::size_of;
 
-use std::cell::Cell;
-use std::fmt::{Debug, Formatter};
+use rayon::prelude::*;
 
+use crate::alphabet::{ALPHABET, get_idx};
+use crate::wordlist::trie::node::{Edge, ImmutableTrieNode, Minimizer, NodeId, NodeRef, PrefixData, push_node, TrieNode};
 
+/// Mutable trie that words are added to before it's built into an `ImmutableTrie`.
+#[derive(Default)]
+pub struct Trie {
+    pub(crate) root: TrieNode,
+}
 
-use typed_arena::Arena;
-
-use crate::wordlist::trie::node::{ImmutableTrieNode, TrieNode};
+/// The searchable form of a trie: a minimized automaton (DAWG) in which identical subtrees
+/// are shared. It owns all of its nodes, which refer to each other by their index in `nodes`,
+/// so it can be shared between threads and moved around freely.
+///
+/// Only the shape of a subtree decides whether it's shared, so suffixes are shared even when
+/// the words they end have different frequencies. Frequencies, tags and suppression belong to
+/// prefixes instead, and are kept in `prefixes`; each edge says how to number the prefix it
+/// leads to.
+#[derive(Clone)]
+pub struct ImmutableTrie {
+    pub(crate) nodes: Vec<ImmutableTrieNode>,
+    /// Every node's outgoing edges, stored contiguously and sorted by letter
+    pub(crate) edges: Vec<Edge>,
+    /// The data of every prefix, in preorder
+    pub(crate) prefixes: Vec<PrefixData>,
+    pub(crate) root: NodeId,
+    /// How many nodes incremental updates have appended since the trie was built; most of them
+    /// replace nodes that can no longer be reached
+    pub(crate) appended: usize,
+}
 
-pub struct Trie<'a> {
-    pub(crate) root: TrieNode<'a>,
-    arena: Arena<TrieNode<'a>>,
-    //path_arena: Arena<String>,
-    pub built: Cell<bool>,
+/// How much memory an `ImmutableTrie` takes up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryUsage {
+    pub nodes: usize,
+    pub edges: usize,
+    pub prefixes: usize,
+    pub bytes: usize,
 }
-pub struct ImmutableTrie<'a> {
-    pub(crate) root: Cell<Option<&'a ImmutableTrieNode<'a>>>,
-    arena: Arena<ImmutableTrieNode<'a>>
+
+impl Display for MemoryUsage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} nodes, {} edges, {} prefixes, {:.1} MiB",
+               self.nodes, self.edges, self.prefixes, self.bytes as f64 / (1024.0 * 1024.0))
+    }
 }
 
-impl<'a> ImmutableTrie<'a> {
-    pub fn new() -> ImmutableTrie<'a> {
+impl ImmutableTrie {
+    pub fn new() -> ImmutableTrie {
         ImmutableTrie {
-            root: Cell::new(None),
-            arena: Arena::new()
+            nodes: vec![ImmutableTrieNode { size: 1, ..Default::default() }],
+            edges: vec![],
+            prefixes: vec![PrefixData::default()],
+            root: 0,
+            appended: 0,
+        }
+    }
+
+    pub(crate) fn root(&self) -> NodeRef<'_> {
+        NodeRef { node: self.node(self.root), prefix: 0, data: &self.prefixes[0] }
+    }
+
+    /// The child of `parent` along `edge`.
+    pub(crate) fn follow<'a>(&'a self, parent: NodeRef<'a>, edge: &Edge) -> NodeRef<'a> {
+        let prefix = parent.prefix + edge.offset;
+        NodeRef { node: self.node(edge.node), prefix, data: &self.prefixes[prefix as usize] }
+    }
+
+    pub(crate) fn node(&self, id: NodeId) -> &ImmutableTrieNode {
+        &self.nodes[id as usize]
+    }
+
+    pub(crate) fn edges(&self, node: &ImmutableTrieNode) -> &[Edge] {
+        &self.edges[node.edge_range()]
+    }
+
+    /// Appends a node with the given edges and returns its id.
+    pub(crate) fn push_node(&mut self, node: ImmutableTrieNode, edges: &[Edge]) -> NodeId {
+        push_node(&mut self.nodes, &mut self.edges, node, edges)
+    }
+
+    pub fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            nodes: self.nodes.len(),
+            edges: self.edges.len(),
+            prefixes: self.prefixes.len(),
+            bytes: size_of::<Self>()
+                + self.nodes.capacity() * size_of::<ImmutableTrieNode>()
+                + self.edges.capacity() * size_of::<Edge>()
+                + self.prefixes.capacity() * size_of::<PrefixData>(),
         }
     }
 }
 
-impl Trie<'_> {
-    fn make_immutable<'a>(&self, immutable: &'a ImmutableTrie<'a>) {
-        immutable.arena.reserve_extend(self.arena.len());
-        let root = self.root.make_immutable(&immutable.arena);
-        immutable.root.set(Some(root));
+impl Default for ImmutableTrie {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-impl Trie<'_> {
-    pub(crate) fn new() -> Self {
-        Trie {
-            root: Default::default(),
-            built: Cell::new(false),
-            arena: Arena::new(),
-            //path_arena: Arena::new(),
+impl Trie {
+    /// Minimizes the subtree under each first letter on its own rayon task, then puts them
+    /// together, sharing what they have in common, under the root.
+    fn make_immutable(&self) -> ImmutableTrie {
+        let subtrees = self.root.children[..].par_iter()
+            .flatten()
+            .map(|child| {
+                let mut minimizer = Minimizer::default();
+                let id = child.make_immutable(&mut minimizer);
+                let mut prefixes = vec![];
+                child.collect_prefixes(&mut prefixes);
+                (child.letter, minimizer, id, prefixes)
+            })
+            .collect::<Vec<_>>();
+
+        let mut minimizer = Minimizer::default();
+        let mut prefixes = Vec::with_capacity(1 + subtrees.iter().map(|subtree| subtree.3.len()).sum::<usize>());
+        prefixes.push(self.root.prefix_data());
+        let mut edges = vec![];
+        for (letter, subtree, id, subtree_prefixes) in subtrees {
+            let ids = minimizer.absorb(&subtree);
+            edges.push(Edge { letter, node: ids[id as usize], offset: 0 });
+            prefixes.extend(subtree_prefixes);
         }
+        let mut root = ImmutableTrieNode { is_terminal: self.root.is_terminal, ..Default::default() };
+        root.connect(&mut edges, &minimizer.nodes);
+        let root = minimizer.add(root, edges);
+        minimizer.nodes.shrink_to_fit();
+        minimizer.edges.shrink_to_fit();
+        ImmutableTrie { nodes: minimizer.nodes, edges: minimizer.edges, prefixes, root, appended: 0 }
     }
 }
 
-
-impl<'a> Trie<'a> {
-    pub fn add<'f>(&'a self, word: &str) {
+impl Trie {
+    pub fn add(&mut self, word: &str) {
         self.add_with_freq(word, 1)
     }
 
-    pub fn add_all<'f, I>(&'a self, items: I)
+    pub fn add_all<'f, I>(&mut self, items: I)
         where I: IntoIterator<Item=&'f str> {
         items.into_iter().for_each(|x| { self.add(x); });
     }
 
-    pub fn add_with_freq<'f>(&'a self, word: &'f str, freq: usize) {
-        assert!(!self.built.get());
-        let mut current = &self.root;
-        {
-            for c in word.chars() {
-                current = current
-                    .get_or_create_child(c, &self.arena)//, &self.path_arena)
-                    .get()
-                    .unwrap()
+    pub fn add_with_freq(&mut self, word: &str, freq: usize) {
+        let end = self.get_or_create(word);
+        end.is_terminal = true;
+        end.freq += freq;
+    }
+
+    /// Marks `word` as suppressed so searches skip it. The word doesn't have to be added yet.
+    pub fn suppress(&mut self, word: &str) {
+        self.get_or_create(word).suppressed = true;
+    }
+
+    /// Adds the tags in `mask` to `word`. The word doesn't have to be added yet.
+    pub fn tag(&mut self, word: &str, mask: u64) {
+        self.get_or_create(word).tags |= mask;
+    }
+
+    /// Adds `(word, freq, tags)` entries, filling in the subtree under each first letter on its
+    /// own rayon task. `on_letter` is called as each of those subtrees is finished.
+    /// Empty words are skipped.
+    pub fn add_parallel<F>(&mut self, entries: Vec<(String, usize, u64)>, on_letter: F)
+        where F: Fn() + Sync {
+        let mut buckets: Vec<Vec<(String, usize, u64)>> = vec![vec![]; ALPHABET.len()];
+        for entry in entries {
+            if let Some(c) = entry.0.chars().next() {
+                buckets[get_idx(c)].push(entry);
             }
         }
+        self.root.children[..].par_iter_mut()
+            .zip(buckets)
+            .enumerate()
+            .filter(|(_, (_, bucket))| !bucket.is_empty())
+            .for_each(|(idx, (child, bucket))| {
+                let child = child.get_or_insert_with(|| Box::new(TrieNode {
+                    letter: ALPHABET[idx] as char,
+                    ..Default::default()
+                }));
+                for (word, freq, tags) in bucket {
+                    let end = child.get_or_create_path(word.chars().skip(1));
+                    end.is_terminal = true;
+                    end.freq += freq;
+                    end.tags |= tags;
+                }
+                on_letter();
+            });
+    }
 
-        let end = current;
-        end.is_terminal.set(true);
-        end.freq.set(end.freq.get() + freq);
+    fn get_or_create(&mut self, word: &str) -> &mut TrieNode {
+        self.root.get_or_create_path(word.chars())
     }
-    pub fn build<'f>(&self, immutable: &'f ImmutableTrie<'f>){
-        self.built.set(true);
-        println!("Decorating...");
+
+    pub fn build(mut self) -> ImmutableTrie {
         self.decorate();
-        println!("Converting...");
-        self.make_immutable(immutable);
+        self.make_immutable()
     }
 
-    fn decorate(&self) {
-        self.root.decorate();
+    /// Decorates the subtree under each first letter in parallel, then the root.
+    fn decorate(&mut self) {
+        self.root.children[..].par_iter_mut()
+            .flatten()
+            .for_each(|child| child.decorate());
+        self.root.weight = self.root.children.iter()
+            .flatten()
+            .map(|child| child.weight)
+            .fold(self.root.unsuppressed_freq(), max);
     }
 }
 
+impl From<&ImmutableTrie> for Trie {
+    /// Turns a built trie back into a mutable one so more words can be added to it.
+    fn from(immutable: &ImmutableTrie) -> Self {
+        fn copy(trie: &ImmutableTrie, node: NodeRef, letter: char) -> TrieNode {
+            let mut copied = TrieNode {
+                letter,
+                is_terminal: node.is_terminal,
+                suppressed: node.data.suppressed,
+                tags: node.data.tags,
+                freq: node.data.freq,
+                ..Default::default()
+            };
+            for edge in trie.edges(node.node) {
+                copied.children[get_idx(edge.letter)] =
+                    Some(Box::new(copy(trie, trie.follow(node, edge), edge.letter)));
+            }
+            copied
+        }
+        Trie { root: copy(immutable, immutable.root(), char::default()) }
+    }
+}
 
-impl<'a> Debug for Trie<'a> {
-    fn fmt<'f>(&'f self, f: &'f mut Formatter<'_>) -> std::fmt::Result {
+
+impl Debug for Trie {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         let mut l = f.debug_list();
         let mut stack = vec![&self.root];
-        while !stack.is_empty() {
-            let x = stack.pop().unwrap();
+        while let Some(x) = stack.pop() {
             l.entry(&x);
-            x.children.iter().for_each(|x|
-                                           x.get().map(|c|
-                                                           stack.push(c)
-                                           ).unwrap_or(()));
+            x.children.iter().flatten().for_each(|c| stack.push(c));
         }
-        // self.root.traverse_prefix(|x| { ml.entry(x); });
         l.finish()
     }
 }
@@ -108,41 +251,39 @@ impl<'a> Debug for Trie<'a> {
 
 #[cfg(test)]
 mod tests {
-    use crate::wordlist::index::Index;
+    use crate::wordlist::trie::searchconfig::SearchConfig;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use crate::wordlist::trie::trie::{ImmutableTrie, Trie};
 
     #[test]
     fn finds_words_in_trie() {
         let words = vec!["HELLO", "HELP", "GOODBYE", "GOOD"];
-        let trie = Trie::new();
+        let mut trie = Trie::default();
         let items = (&words).iter().map(|x| *x);
         trie.add_all(items);
-        let immut = ImmutableTrie::new();
-        trie.build(&immut);
-        (&words).iter().for_each(|word| assert!(trie.contains(&word)));
+        let immut = trie.build();
+        (&words).iter().for_each(|word| assert!(immut.contains(&word)));
     }
 
     #[test]
     fn doesnt_finds_words_not_in_trie() {
         let words = vec!["HELLO", "HELP", "GOODBYE", "GOOD"];
         let bad_words = vec!["HE", "H", "LOL", "BANANA"];
-        let trie = Trie::new();
+        let mut trie = Trie::default();
         trie.add_all((&words).iter().map(|x| *x));
-        let immut = ImmutableTrie::new();
-        trie.build(&immut);
-        (&bad_words).iter().for_each(|word| assert!(!trie.contains(&word)));
+        let immut = trie.build();
+        (&bad_words).iter().for_each(|word| assert!(!immut.contains(&word)));
     }
 
 
     #[test]
     fn query_words_in_trie() {
         let words = vec!["HELLO", "HELP", "GOODBYE", "GOOD"];
-        let trie = Trie::new();
+        let mut trie = Trie::default();
         trie.add_all((&words).iter().map(|x| *x));
-        let immut = ImmutableTrie::new();
-        trie.build(&immut);
+        let immut = trie.build();
 
-        let mut result = trie.query_regex("H.L*(O|P)");
+        let mut result = immut.query_regex("H.L*(O|P)", &SearchConfig::new());
         result.sort();
 
         assert_eq!(result, vec!["HELLO", "HELP"])
@@ -161,18 +302,108 @@ mod tests {
     #[test]
     fn test_anagram() {
         let words = vec!["HELLO", "HELP", "GOODBYE", "GOOD"];
-        let trie = Trie::new();
+        let mut trie = Trie::default();
         trie.add_all((&words).iter().map(|x| *x));
-        let immut = ImmutableTrie::new();
-        trie.build(&immut);
+        let immut = trie.build();
 
-        assert_eq!(trie.query_anagram("OLEHL"), vec!["HELLO"]);
-        assert!(trie.query_anagram("LEHL").is_empty());
-        assert!(trie.query_anagram("LELO").is_empty());
-        assert!(trie.query_anagram("DOG").is_empty());
-        assert_eq!(trie.query_anagram("OOGD"), vec!["GOOD"]);
+        assert_eq!(immut.query_anagram("OLEHL", &SearchConfig::new()), vec!["HELLO"]);
+        assert!(immut.query_anagram("LEHL", &SearchConfig::new()).is_empty());
+        assert!(immut.query_anagram("LELO", &SearchConfig::new()).is_empty());
+        assert!(immut.query_anagram("DOG", &SearchConfig::new()).is_empty());
+        assert_eq!(immut.query_anagram("OOGD", &SearchConfig::new()), vec!["GOOD"]);
     }
 
-}
+    #[test]
+    fn rebuild_from_immutable() {
+        let mut trie = Trie::default();
+        trie.add_all(vec!["HELLO", "HELP"]);
+        trie.suppress("HELLO");
+        let immut = trie.build();
+
+        let mut trie = Trie::from(&immut);
+        trie.add_with_freq("HELP", 2);
+        trie.add("GOOD");
+        let immut = trie.build();
+
+        assert!(immut.contains("GOOD"));
+        assert_eq!(immut.root().data.weight, 3);
+        assert_eq!(immut.query_regex("HEL(LO|P)", &SearchConfig::new()), vec!["HELP"]);
+    }
+
+    #[test]
+    fn nodes_know_word_depths() {
+        let mut trie = Trie::default();
+        trie.add_all(vec!["HELP", "HELPER", "HELLO"]);
+        let mut immut = trie.build();
+
+        let depths = |immut: &ImmutableTrie, prefix: &str| {
+            let node = immut.get_node(prefix, Some(immut.root())).unwrap();
+            (node.min_depth, node.max_depth)
+        };
+        assert_eq!(depths(&immut, ""), (4, 6));
+        assert_eq!(depths(&immut, "HELP"), (0, 2));
+        assert_eq!(depths(&immut, "HELL"), (1, 1));
+
+        immut.remove_incremental("HELP");
+        immut.add_incremental("HE", 1);
+        assert_eq!(depths(&immut, ""), (2, 6));
+        assert_eq!(depths(&immut, "HELP"), (2, 2));
+    }
+
+    #[test]
+    fn shares_identical_suffixes() {
+        let mut trie = Trie::default();
+        for (freq, word) in ["TESTING", "RESTING", "NESTING", "TEST", "REST"].iter().enumerate() {
+            trie.add_with_freq(word, freq + 1);
+        }
+        let immut = trie.build();
+
+        // the root, one branch shared by T and R and another for N (NEST isn't a word), and
+        // then a single ING at the end of all three, even though their frequencies differ
+        assert_eq!(immut.memory_usage().nodes, 1 + 2 * 4 + 3);
+        assert_eq!(immut.memory_usage().prefixes, 1 + 3 * 7);
+        let mut result = immut.query_regex("(T|R|N)EST(ING)?", &SearchConfig::new());
+        result.sort();
+        assert_eq!(result, vec!["NESTING", "REST", "RESTING", "TEST", "TESTING"]);
+        assert_eq!(result.iter().map(|result| result.word_freqs[0]).collect::<Vec<_>>(), vec![3, 5, 2, 4, 1]);
+        assert_eq!(immut.get_node("RES", Some(immut.root())).unwrap().data.weight, 5);
+        assert_eq!(immut.get_node("TES", Some(immut.root())).unwrap().data.weight, 4);
+    }
 
+    #[test]
+    fn add_parallel_matches_add() {
+        let words = ["HELLO", "HELP", "GOODBYE", "GOOD", "BYE", "ZEBRA"];
+        let mut trie = Trie::default();
+        words.iter().for_each(|word| trie.add_with_freq(word, word.len()));
+        trie.tag("GOOD", 1);
+        let expected = trie.build();
+
+        let mut trie = Trie::default();
+        trie.add("HELP");
+        let finished = AtomicUsize::new(0);
+        trie.add_parallel(words.iter()
+                              .map(|word| (word.to_string(), word.len() - (*word == "HELP") as usize,
+                                           (*word == "GOOD") as u64))
+                              .collect(),
+                          || { finished.fetch_add(1, Ordering::Relaxed); });
+        let immut = trie.build();
+
+        assert_eq!(finished.load(Ordering::Relaxed), 4);
+        assert_eq!(immut.memory_usage(), expected.memory_usage());
+        // the frequencies differ, but HELP and HELLO still share the node ending them with BYE
+        let mut uniform = Trie::default();
+        uniform.add_all(words.iter().copied());
+        assert_eq!(immut.memory_usage().nodes, uniform.build().memory_usage().nodes);
+        assert_eq!(immut.get_node("BYE", Some(immut.root())).unwrap().node,
+                   immut.get_node("HELP", Some(immut.root())).unwrap().node);
+        assert_eq!(immut.query_anagram("DOGO", &SearchConfig::new()), expected.query_anagram("DOGO", &SearchConfig::new()));
+        assert_eq!(immut.query_regex("(HEL.|GOOD|BYE)", &SearchConfig::new()), expected.query_regex("(HEL.|GOOD|BYE)", &SearchConfig::new()));
+        assert_eq!(immut.get_node("GOOD", Some(immut.root())).unwrap().data.tags, 1);
+    }
 
+    #[test]
+    fn immutable_trie_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync + 'static>() {}
+        assert_send_sync::<ImmutableTrie>();
+    }
+}
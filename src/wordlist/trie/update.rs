@@ -0,0 +1,245 @@
+use std::cmp::max;
+
+use crate::alphabet::get_idx;
+use crate::wordlist::trie::node::{Edge, ImmutableTrieNode, NodeId, PrefixData};
+use crate::wordlist::trie::trie::ImmutableTrie;
+
+/// Below this many appended nodes an index isn't worth rebuilding, however small it is.
+const MIN_FRAGMENTATION: usize = 4096;
+
+impl ImmutableTrie {
+    /// Whether incremental updates have left enough unreachable nodes behind (more than a
+    /// quarter of the index) that it should be rebuilt, e.g. with `Trie::from` and `build`.
+    pub fn is_fragmented(&self) -> bool {
+        self.appended > MIN_FRAGMENTATION && self.appended * 4 > self.nodes.len()
+    }
+
+    /// Adds `freq` to `word` without rebuilding, fixing up the weights along its path.
+    pub fn add_incremental(&mut self, word: &str, freq: usize) {
+        self.update_path(word, true, |end, data| {
+            end.is_terminal = true;
+            data.freq += freq;
+            true
+        });
+    }
+
+    /// Removes `word`, returning whether it was there.
+    pub fn remove_incremental(&mut self, word: &str) -> bool {
+        self.update_path(word, false, |end, data| {
+            let was_terminal = end.is_terminal;
+            end.is_terminal = false;
+            data.freq = 0;
+            was_terminal
+        })
+    }
+
+    /// Suppresses `word` so searches skip it, returning whether it was there. Like
+    /// `Trie::suppress`, this is remembered even if the word is only added later.
+    pub fn suppress_incremental(&mut self, word: &str) -> bool {
+        let mut was_terminal = false;
+        self.update_path(word, true, |end, data| {
+            was_terminal = end.is_terminal;
+            data.suppressed = true;
+            true
+        });
+        was_terminal
+    }
+
+    /// Adds the tags in `mask` to `word`, returning whether it was there.
+    pub fn tag_incremental(&mut self, word: &str, mask: u64) -> bool {
+        let mut was_terminal = false;
+        self.update_path(word, true, |end, data| {
+            was_terminal = end.is_terminal;
+            data.tags |= mask;
+            true
+        });
+        was_terminal
+    }
+
+    /// Applies `f` to a copy of the node for `word` and its prefix's data, then recomputes the
+    /// weights of the prefixes above it. If `f` changes whether the node ends a word, every node
+    /// on the path is copied, recomputing their depths, before swapping in the new root.
+    ///
+    /// Nodes are only ever appended, never modified, so nodes shared with other paths aren't
+    /// affected; the old copies are left behind until the trie is rebuilt (see
+    /// `is_fragmented`). Missing nodes (and their prefixes) are created if `create` is set,
+    /// otherwise nothing changes and `false` is returned; `f` can also return `false` to
+    /// abandon the update.
+    ///
+    /// Prefixes are numbered in preorder, so creating one shifts the data of every prefix after
+    /// it: adding a new word takes time proportional to the size of the trie.
+    fn update_path<F>(&mut self, word: &str, create: bool, f: F) -> bool
+        where F: FnOnce(&mut ImmutableTrieNode, &mut PrefixData) -> bool {
+        let letters = word.chars().collect::<Vec<_>>();
+        let old = self.walk(&letters);
+        let exists = old.len() > letters.len();
+        if !exists && !create {
+            return false;
+        }
+        let (mut end, mut data) = match old.get(letters.len()) {
+            Some(&(id, prefix)) => (self.node(id).clone(), self.prefixes[prefix as usize]),
+            None => (ImmutableTrieNode::default(), PrefixData::default()),
+        };
+        if !f(&mut end, &mut data) {
+            return false;
+        }
+
+        if !exists || end.is_terminal != self.node(old[letters.len()].0).is_terminal {
+            let mut replacement: Option<NodeId> = None;
+            for depth in (0..=letters.len()).rev() {
+                let (mut node, mut edges) = match old.get(depth) {
+                    Some(&(id, _)) => (self.node(id).clone(), self.edges(self.node(id)).to_vec()),
+                    None => (ImmutableTrieNode::default(), vec![])
+                };
+                match replacement {
+                    Some(id) => {
+                        let edge = Edge { letter: letters[depth], node: id, offset: 0 };
+                        match edges.binary_search_by_key(&get_idx(edge.letter), |x| get_idx(x.letter)) {
+                            Ok(idx) => edges[idx] = edge,
+                            Err(idx) => edges.insert(idx, edge),
+                        }
+                    }
+                    None => node.is_terminal = end.is_terminal,
+                }
+                node.connect(&mut edges, &self.nodes);
+                replacement = Some(self.push_node(node, &edges));
+                self.appended += 1;
+            }
+            self.root = replacement.unwrap();
+        }
+
+        let new = self.walk(&letters);
+        if !exists {
+            // the new prefixes come one after another, starting with the first missing one
+            let first = new[old.len()].1 as usize;
+            self.prefixes.splice(first..first, vec![PrefixData::default(); new.len() - old.len()]);
+        }
+        self.prefixes[new[letters.len()].1 as usize] = data;
+        for &(id, prefix) in new.iter().rev() {
+            let weight = self.edges(self.node(id)).iter()
+                .map(|edge| self.prefixes[(prefix + edge.offset) as usize].weight)
+                .fold(self.prefixes[prefix as usize].unsuppressed_freq(), max);
+            self.prefixes[prefix as usize].weight = weight;
+        }
+        true
+    }
+
+    /// The nodes along `letters` from the root and the numbers of their prefixes, for as far
+    /// as they go.
+    fn walk(&self, letters: &[char]) -> Vec<(NodeId, u32)> {
+        let mut path = vec![(self.root, 0)];
+        for &c in letters {
+            let (id, prefix) = *path.last().unwrap();
+            match self.edges(self.node(id)).iter().find(|edge| edge.letter == c) {
+                Some(edge) => path.push((edge.node, prefix + edge.offset)),
+                None => break,
+            }
+        }
+        path
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::wordlist::trie::searchconfig::SearchConfig;
+    use crate::wordlist::trie::trie::Trie;
+
+    #[test]
+    fn add_after_build() {
+        let mut trie = Trie::default();
+        trie.add_all(vec!["HELLO", "HELP"]);
+        let mut immut = trie.build();
+        let old = immut.clone();
+
+        immut.add_incremental("HELM", 4);
+        immut.add_incremental("GOOD", 2);
+
+        assert!(immut.contains("HELM"));
+        assert!(immut.contains("GOOD"));
+        assert_eq!(immut.root().data.weight, 4);
+        assert_eq!(old.root().data.weight, 1);
+        assert!(!old.contains("HELM"));
+
+        let mut result = immut.query_regex_results("(HEL.|GOOD)", &SearchConfig::new());
+        result.sort();
+        assert_eq!(result, vec!["GOOD", "HELM", "HELP"]);
+    }
+
+    #[test]
+    fn remove_after_build() {
+        let mut trie = Trie::default();
+        trie.add_all(vec!["HELLO", "HELP", "HELPER"]);
+        let mut immut = trie.build();
+
+        assert!(immut.remove_incremental("HELP"));
+        assert!(!immut.remove_incremental("HELP"));
+        assert!(!immut.remove_incremental("HEL"));
+        assert!(!immut.remove_incremental("GOOD"));
+
+        assert!(!immut.contains("HELP"));
+        assert!(immut.contains("HELPER"));
+        assert_eq!(immut.root().data.weight, 1);
+        assert_eq!(immut.query_regex_results("HELP(ER)?", &SearchConfig::new()), vec!["HELPER"]);
+    }
+
+    #[test]
+    fn suppress_after_build() {
+        let mut trie = Trie::default();
+        trie.add_all(vec!["HELLO", "HELP", "HELPER"]);
+        let mut immut = trie.build();
+
+        assert!(immut.suppress_incremental("HELP"));
+        assert!(!immut.suppress_incremental("HEL"));
+
+        assert!(immut.contains("HELP"));
+        assert_eq!(immut.root().data.weight, 1);
+        assert_eq!(immut.query_regex_results("HELP(ER)?", &SearchConfig::new()), vec!["HELPER"]);
+        assert_eq!(immut.query_regex("HELP(ER)?", &SearchConfig::new()), vec!["HELPER"]);
+    }
+
+    #[test]
+    fn update_words_sharing_a_suffix() {
+        let mut trie = Trie::default();
+        trie.add_with_freq("TESTING", 1);
+        trie.add_with_freq("RESTING", 2);
+        let mut immut = trie.build();
+
+        immut.add_incremental("TESTING", 5);
+        immut.tag_incremental("RESTING", 1);
+        immut.add_incremental("TESTINGS", 1);
+        let freq = |immut: &crate::wordlist::trie::trie::ImmutableTrie, word: &str| {
+            immut.get_node(word, Some(immut.root())).unwrap().data.freq
+        };
+        assert_eq!((freq(&immut, "TESTING"), freq(&immut, "RESTING"), freq(&immut, "TESTINGS")), (6, 2, 1));
+        assert_eq!(immut.get_node("RESTING", Some(immut.root())).unwrap().data.tags, 1);
+        assert_eq!(immut.get_node("TESTING", Some(immut.root())).unwrap().data.tags, 0);
+        assert!(!immut.contains("RESTINGS"));
+        assert_eq!(immut.root().data.weight, 6);
+        assert_eq!(immut.get_node("R", Some(immut.root())).unwrap().data.weight, 2);
+    }
+
+    #[test]
+    fn old_paths_are_counted_until_rebuilt() {
+        let mut trie = Trie::default();
+        trie.add_all(vec!["HELLO", "HELP"]);
+        let mut immut = trie.build();
+
+        let letters = "ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+        let words = letters.chars()
+            .flat_map(|a| letters.chars().map(move |b| format!("HELLO{}{}", a, b)))
+            .collect::<Vec<_>>();
+        for word in &words {
+            immut.add_incremental(word, 1);
+        }
+        // every word copied its whole path, though only the last node of each is still needed
+        assert!(immut.is_fragmented());
+        assert!(immut.memory_usage().nodes > 8 * words.len());
+
+        let rebuilt = Trie::from(&immut).build();
+        assert!(!rebuilt.is_fragmented());
+        assert!(rebuilt.memory_usage().nodes < immut.memory_usage().nodes / 4);
+        assert!(words.iter().all(|word| rebuilt.contains(word)));
+        assert!(rebuilt.contains("HELLO"));
+    }
+}
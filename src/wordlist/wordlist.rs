@@ -1,20 +1,98 @@
 use std::cmp::max;
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::{BufRead, BufReader};
-
-use std::time::Instant;
+use std::io::{BufRead, BufReader, ErrorKind};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
 use serde_json::from_str;
 
 use typed_builder::TypedBuilder;
-use crate::alphabet::normalize;
+use crate::alphabet::Folding;
+use crate::wordlist::bigrams::{BigramModel, BigramScorer};
+use crate::wordlist::paged_search::{ContinuationToken, PagedSearch};
+use crate::wordlist::search_iter::SearchIter;
+use crate::wordlist::trie::constraint::{Anagram, Constraint, Regex};
+use crate::wordlist::trie::executor::{Executor, SingleThreaded, ThreadPool};
+use crate::wordlist::trie::explain::Explanation;
 use crate::wordlist::trie::multithreaded_search::ResultCallback;
-use crate::wordlist::trie::searchconfig::SearchConfig;
-use crate::wordlist::trie::trie::{ImmutableTrie, Trie};
+use crate::wordlist::trie::searchconfig::{SearchConfig, TagFilter};
+use crate::wordlist::trie::searchresult::{SearchResult, SearchSummary};
+use crate::wordlist::trie::trie::{ImmutableTrie, MemoryUsage, Trie};
+
+/// How often (in lines) parsing progress is reported.
+const PROGRESS_INTERVAL: usize = 100000;
+
+/// How long a parked search waits to be resumed, unless `Wordlist::set_park_timeout` says otherwise.
+const PARK_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// The lines of `reader`, skipping (and counting in `failures`) lines that can't be read, such
+/// as ones that aren't valid UTF-8. Any other error also counts as a failure, and ends the lines,
+/// since reading on would most likely hit it again (as it would forever on a directory).
+pub(crate) fn readable_lines<'a, R: BufRead + 'a>(reader: R, failures: &'a mut usize)
+                                                  -> impl Iterator<Item=String> + 'a {
+    let mut lines = reader.lines();
+    std::iter::from_fn(move || loop {
+        match lines.next()? {
+            Ok(line) => return Some(line),
+            Err(e) => {
+                *failures += 1;
+                if e.kind() != ErrorKind::InvalidData {
+                    return None;
+                }
+            }
+        }
+    }).fuse()
+}
 
-pub struct Wordlist<'a> {
-    trie: Trie<'a>,
-    immut_trie: ImmutableTrie<'a>,
+/// How far along `Wordlist::load_reader_with_progress` is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoadProgress {
+    /// All `lines` have been read from the input. Parsing starts before this, so it can come
+    /// after some `Parsed`s, but always before the last one.
+    Read { lines: usize },
+    /// `lines` have been parsed, of a `total` that's only known once the input has all been read
+    Parsed { lines: usize, total: Option<usize> },
+    /// The words under `letters` first letters have been inserted
+    Inserted { letters: usize },
+    /// Parsing and inserting finished, and the index is about to be built
+    Done { words: usize, failures: usize, elapsed: Duration },
+}
+
+/// Something a `Wordlist` is doing, passed to its event handler.
+#[derive(Clone, Debug, PartialEq)]
+pub enum WordlistEvent {
+    ReadingWords { path: String },
+    ReadingExclusions { path: String },
+    ReadingTags { path: String, tag: String },
+    ReadingBigrams { path: String },
+    /// An exclusion or tag file has been read
+    ListLoaded { words: usize, failures: usize },
+    /// A bigram count file has been read
+    BigramsLoaded { pairs: usize, failures: usize },
+    /// Progress of a `load_reader` (or `load_file`)
+    Load(LoadProgress),
+    /// The index is being rebuilt
+    Building,
+    Built { elapsed: Duration, memory: MemoryUsage },
+}
+
+/// A searchable wordlist. It can be shared between threads (e.g. in an `Arc`); searches run on a
+/// snapshot of the index, so they aren't disturbed by words being loaded or changed meanwhile.
+pub struct Wordlist {
+    index: RwLock<Arc<ImmutableTrie>>,
+    /// Held while the index is being changed, so concurrent changes don't overwrite each other
+    writer: Mutex<()>,
+    folding: Folding,
+    tag_names: RwLock<Vec<String>>,
+    events: Box<dyn Fn(&WordlistEvent) + Send + Sync>,
+    name: Option<String>,
+    /// Word pair counts for ranking phrases, if any have been loaded
+    bigrams: RwLock<Option<Arc<BigramModel>>>,
+    /// Paged searches waiting for someone to ask for more results, and when they were parked
+    parked: Mutex<HashMap<ContinuationToken, (Instant, PagedSearch)>>,
+    park_timeout: Duration,
 }
 
 
@@ -26,19 +104,35 @@ pub struct FileFormat {
     word_column: Option<usize>,
     #[builder(default, setter(strip_option))]
     freq_column: Option<usize>,
+    /// Column holding comma-separated tags for the entry, if any
+    #[builder(default, setter(strip_option))]
+    tag_column: Option<usize>,
 }
 
 impl FileFormat {
-    fn parse_line<'a>(&self, line: &'a str) -> Result<(&'a str, isize), String> {
+    /// Whether lines have a frequency column; without a delimiter every word has frequency 1.
+    pub fn has_freqs(&self) -> bool {
+        self.delimiter.is_some()
+    }
+
+    /// The word, frequency and tags on `line`, or why it can't be loaded (too few columns, or a
+    /// frequency that isn't a whole number of at least 0, e.g. in a header row).
+    fn parse_line<'a>(&self, line: &'a str) -> Result<(&'a str, usize, Vec<&'a str>), String> {
         if self.delimiter.is_none() {
-            Ok((line, 1))
+            Ok((line, 1, vec![]))
         } else {
             let columns = line.split(self.delimiter.unwrap()).collect::<Vec<_>>();
             let word_idx = self.word_column.unwrap_or(0);
             let freq_idx = self.freq_column.unwrap_or(1);
-            if columns.len() >= max(word_idx, freq_idx) {
-                Ok((columns.get(word_idx).unwrap(),
-                    from_str::<isize>(columns.get(freq_idx).unwrap()).unwrap()))
+            if columns.len() > max(word_idx, freq_idx) {
+                let tags = self.tag_column
+                    .and_then(|idx| columns.get(idx))
+                    .map(|tags| tags.split(',').map(str::trim).filter(|x| !x.is_empty()).collect())
+                    .unwrap_or_default();
+                let freq = columns[freq_idx];
+                let freq = from_str::<isize>(freq).map_err(|_| format!("Bad frequency {}", freq))?;
+                let freq = usize::try_from(freq).map_err(|_| format!("Negative frequency {}", freq))?;
+                Ok((columns[word_idx], freq, tags))
             } else {
                 Err("Not enough columns".to_string())
             }
@@ -46,91 +140,1043 @@ impl FileFormat {
     }
 }
 
-impl<'a> Wordlist<'a> {
-    pub fn new() -> Wordlist<'a> {
-        Wordlist { trie: Trie::new(), immut_trie: ImmutableTrie::new() }
+impl Wordlist {
+    pub fn new() -> Wordlist {
+        Self::with_folding(Folding::default())
+    }
+
+    /// Creates a wordlist that folds both loaded words and queries with `folding`.
+    pub fn with_folding(folding: Folding) -> Wordlist {
+        Wordlist {
+            index: RwLock::new(Arc::new(ImmutableTrie::new())),
+            writer: Mutex::new(()),
+            folding,
+            tag_names: RwLock::new(vec![]),
+            events: Box::new(|_| {}),
+            name: None,
+            bigrams: RwLock::new(None),
+            parked: Mutex::new(HashMap::new()),
+            park_timeout: PARK_TIMEOUT,
+        }
+    }
+
+    /// Names the wordlist; search results say which wordlist they came from with this.
+    pub fn set_name(&mut self, name: &str) {
+        self.name = Some(name.to_string());
+    }
+
+    /// How long a parked search is kept for `resume` before it's dropped; 10 minutes by default.
+    pub fn set_park_timeout(&mut self, timeout: Duration) {
+        self.park_timeout = timeout;
+    }
+
+    /// Sends what the wordlist is doing (files being read, loading progress, timings) to
+    /// `handler`. By default it's discarded.
+    pub fn set_event_handler<F>(&mut self, handler: F)
+        where F: Fn(&WordlistEvent) + Send + Sync + 'static {
+        self.events = Box::new(handler);
     }
 
-    pub fn load_file<'f>(&'a self, filename: &str, format: FileFormat) {
-        println!("Reading words from {:#?}", &filename);
+    /// The current index. It's immutable, so it can be searched from any thread for as long as
+    /// needed; later changes to the wordlist don't affect it.
+    pub fn snapshot(&self) -> Arc<ImmutableTrie> {
+        self.index.read().unwrap().clone()
+    }
+
+    pub fn load_file(&self, filename: &str, format: FileFormat) {
+        (self.events)(&WordlistEvent::ReadingWords { path: filename.to_string() });
 
         let file = File::open(filename).unwrap();
-        let buf_reader = BufReader::new(file);
-
-        let trie = &self.trie;
-        let immut = &self.immut_trie;
-        let mut count: usize = 0;
-        let mut failures: usize = 0;
-
-        let mut start = Instant::now();
-
-        let lines: Vec<Result<String, _>> = buf_reader.lines().collect();
-
-        println!("Reading took {}", start.elapsed().as_secs_f64());
-
-        start = Instant::now();
-        //buf_reader.lines()
-        lines.iter().for_each(
-            |x| match x {
-                Ok(line) => {
-                    if line.len() > 0 {
-                        match format.parse_line(line) {
-                            Ok((word, freq)) => {
-                                trie.add_with_freq(&*normalize(&word), freq.try_into().unwrap());
-                                count += 1;
-                                if count % 100000 == 0 {
-                                    println!("{} {}", count, normalize(&word));
-                                }
-                            }
+        self.load_reader(BufReader::new(file), format)
+    }
+
+    /// Loads words from any buffered reader (e.g. stdin) and rebuilds the index.
+    pub fn load_reader<R: BufRead>(&self, buf_reader: R, format: FileFormat) {
+        self.load_reader_with_progress(buf_reader, format,
+                                       |progress| (self.events)(&WordlistEvent::Load(progress)))
+    }
+
+    /// Like `load_reader`, but reports how far along it is to `progress` instead of the event
+    /// handler.
+    ///
+    /// Lines are parsed and normalized in parallel, a chunk at a time as they're read, and the
+    /// words under each first letter are inserted on their own thread, so `progress` may be
+    /// called from several threads at once. Reading stops at the first error other than a line
+    /// that isn't valid UTF-8, which counts as a failure.
+    pub fn load_reader_with_progress<R: BufRead, P>(&self, buf_reader: R, format: FileFormat, progress: P)
+        where P: Fn(LoadProgress) + Sync {
+        let _writer = self.writer.lock().unwrap();
+        let start = Instant::now();
+
+        let total = OnceLock::new();
+        let parsed = AtomicUsize::new(0);
+        let failures = AtomicUsize::new(0);
+        let mut unreadable = 0;
+        let chunks = Mutex::new(vec![]);
+        rayon::in_place_scope(|scope| {
+            let mut lines = readable_lines(buf_reader, &mut unreadable);
+            let mut chunks_read = std::iter::from_fn(|| {
+                Some(lines.by_ref().take(PROGRESS_INTERVAL).collect::<Vec<_>>()).filter(|chunk| !chunk.is_empty())
+            }).fuse().enumerate().peekable();
+            let finish_reading = |lines| {
+                total.set(lines).unwrap();
+                progress(LoadProgress::Read { lines });
+            };
+            let (format, total, parsed, failures, chunks, progress) = (&format, &total, &parsed, &failures, &chunks, &progress);
+            let mut read = 0;
+            while let Some((idx, chunk)) = chunks_read.next() {
+                read += chunk.len();
+                // reading is over before the last chunk is handed out, so the last `Parsed` has the total
+                if chunks_read.peek().is_none() {
+                    finish_reading(read);
+                }
+                scope.spawn(move |_| {
+                    let entries = chunk.iter()
+                        .filter(|line| !line.is_empty())
+                        .filter_map(|line| match format.parse_line(line) {
+                            Ok((word, freq, tags)) => Some((self.folding.normalize(word), freq,
+                                                            tags.into_iter().map(str::to_string).collect::<Vec<_>>())),
                             Err(_e) => {
-                                failures += 1;
+                                failures.fetch_add(1, Ordering::Relaxed);
+                                None
                             }
-                        }
-                    }
-                }
-                Err(_e) => {
-                    //eprintln!("Line #{} - {}", count, e);
-                    failures += 1;
+                        })
+                        .filter(|(word, _, _)| !word.is_empty())
+                        .collect::<Vec<_>>();
+                    let done = parsed.fetch_add(chunk.len(), Ordering::Relaxed) + chunk.len();
+                    progress(LoadProgress::Parsed { lines: done, total: total.get().copied() });
+                    chunks.lock().unwrap().push((idx, entries));
+                });
+            }
+            if read == 0 {
+                finish_reading(0);
+            }
+        });
+        failures.fetch_add(unreadable, Ordering::Relaxed);
+        let mut chunks = chunks.into_inner().unwrap();
+        chunks.sort_unstable_by_key(|(idx, _)| *idx);
+        let entries = chunks.into_iter().flat_map(|(_, entries)| entries);
+
+        // tags get their bits in the order they first appear, so this part stays sequential.
+        // Words keep the tags that fit; a line with tags past the 64th counts as a failure.
+        let entries = entries
+            .map(|(word, freq, tags)| {
+                let bits = tags.iter().map(|tag| self.tag_bit(tag)).collect::<Vec<_>>();
+                if bits.iter().any(Result::is_err) {
+                    failures.fetch_add(1, Ordering::Relaxed);
                 }
-            });
-        let elapsed = start.elapsed();
-        println!("Read {} words in {}s ({} kwps) [{} failures ({:.2}%)]",
-                 count, (elapsed.as_millis() as f64) / 1000.0, (count as f64) / (elapsed.as_millis() as f64),
-                 failures, 100.0 * (failures as f64) / (count as f64));
+                (word, freq, bits.into_iter().flatten().fold(0, |mask, bit| mask | bit))
+            })
+            .collect::<Vec<_>>();
+        let words = entries.len();
 
+        let mut trie = Trie::from(&*self.snapshot());
+        let letters = AtomicUsize::new(0);
+        trie.add_parallel(entries, || {
+            let done = letters.fetch_add(1, Ordering::Relaxed) + 1;
+            progress(LoadProgress::Inserted { letters: done });
+        });
+        progress(LoadProgress::Done {
+            words,
+            failures: failures.into_inner(),
+            elapsed: start.elapsed(),
+        });
 
-        let start_build = Instant::now();
-        {
-            trie.build(immut);
+        self.build(trie);
+    }
+
+    /// Adds `(word, freq)` pairs, normalizing each word, and rebuilds the index.
+    pub fn extend<I>(&self, words: I)
+        where I: IntoIterator<Item=(String, usize)> {
+        self.rebuild(|trie| words.into_iter().for_each(|(word, freq)| {
+            let word = self.folding.normalize(&word);
+            if !word.is_empty() {
+                trie.add_with_freq(&word, freq);
+            }
+        }));
+    }
+
+    /// Adds `freq` occurrences of `word` to a loaded wordlist without rebuilding it.
+    ///
+    /// Only the nodes along the word's path are replaced, so a search that is already running
+    /// keeps seeing the wordlist as it was when it started. That doesn't make every update
+    /// cheap, though. Prefixes are kept in one table in preorder, so a word that's new to the
+    /// wordlist shifts every prefix after its own: it takes time proportional to the size of
+    /// the index. And if a search (or a `snapshot`) still holds the index, the first change
+    /// copies all of it. Use `extend` to add many words at once.
+    pub fn add_word(&self, word: &str, freq: usize) {
+        let word = self.folding.normalize(word);
+        if !word.is_empty() {
+            self.update(|index| index.add_incremental(&word, freq));
+        }
+    }
+
+    /// Removes `word` from a loaded wordlist without rebuilding it, returning whether it was there.
+    pub fn remove_word(&self, word: &str) -> bool {
+        let word = self.folding.normalize(word);
+        self.update(|index| index.remove_incremental(&word))
+    }
+
+    /// Loads an exclusion list (one word per line). Excluded words stay in the wordlist but are
+    /// skipped by every search; this can be called before or after the words themselves are loaded.
+    /// Fails if the file can't be opened.
+    pub fn load_exclusions(&self, filename: &str) -> Result<(), String> {
+        (self.events)(&WordlistEvent::ReadingExclusions { path: filename.to_string() });
+
+        let file = File::open(filename).map_err(|e| e.to_string())?;
+        self.load_exclusions_reader(BufReader::new(file));
+        Ok(())
+    }
+
+    pub fn load_exclusions_reader<R: BufRead>(&self, buf_reader: R) {
+        let words = self.read_word_list(buf_reader);
+        self.rebuild(|trie| words.iter().for_each(|word| trie.suppress(word)));
+    }
+
+    /// Excludes a single word from search results, returning whether it was in the wordlist.
+    pub fn ban_word(&self, word: &str) -> bool {
+        let word = self.folding.normalize(word);
+        self.update(|index| index.suppress_incremental(&word))
+    }
+
+    /// Loads word pair counts (lines of `FIRST SECOND COUNT`), replacing any loaded before.
+    /// From then on, searches whose config doesn't pick a scorer rank results with a
+    /// `BigramScorer`, so that phrases people actually say come first. Fails if the file can't be
+    /// opened, leaving the model as it was.
+    pub fn load_bigrams(&self, filename: &str) -> Result<(), String> {
+        (self.events)(&WordlistEvent::ReadingBigrams { path: filename.to_string() });
+
+        let file = File::open(filename).map_err(|e| e.to_string())?;
+        self.load_bigrams_reader(BufReader::new(file));
+        Ok(())
+    }
+
+    pub fn load_bigrams_reader<R: BufRead>(&self, buf_reader: R) {
+        let (model, failures) = BigramModel::read(buf_reader, &self.folding);
+        (self.events)(&WordlistEvent::BigramsLoaded { pairs: model.len(), failures });
+        *self.bigrams.write().unwrap() = Some(Arc::new(model));
+    }
+
+    /// `config`, with the bigram scorer filled in if there's a model and no other scorer.
+    fn configure(&self, config: &SearchConfig) -> SearchConfig {
+        let mut config = config.clone();
+        if config.scorer.is_none() {
+            if let Some(model) = self.bigrams.read().unwrap().clone() {
+                config.scorer = Some(Arc::new(BigramScorer { model }));
+            }
+        }
+        config
+    }
+
+    /// Tags every word in a category file (one word per line) with `tag`. Fails if the file can't
+    /// be opened, or the wordlist already has as many tags as it can hold.
+    pub fn load_tag_file(&self, filename: &str, tag: &str) -> Result<(), String> {
+        (self.events)(&WordlistEvent::ReadingTags { path: filename.to_string(), tag: tag.to_string() });
+
+        let file = File::open(filename).map_err(|e| e.to_string())?;
+        self.load_tags_reader(BufReader::new(file), tag)
+    }
+
+    pub fn load_tags_reader<R: BufRead>(&self, buf_reader: R, tag: &str) -> Result<(), String> {
+        let mask = self.tag_bit(tag)?;
+        let words = self.read_word_list(buf_reader);
+        self.rebuild(|trie| words.iter().for_each(|word| trie.tag(word, mask)));
+        Ok(())
+    }
+
+    /// All the tags seen so far.
+    pub fn tags(&self) -> Vec<String> {
+        self.tag_names.read().unwrap().clone()
+    }
+
+    /// A filter for `SearchConfig::tags` that keeps words carrying all of `tags`.
+    pub fn tag_filter(&self, tags: &[&str]) -> Result<TagFilter, String> {
+        let names = self.tag_names.read().unwrap();
+        let mut mask = 0;
+        for tag in tags {
+            let tag = tag.trim().to_lowercase();
+            match names.iter().position(|x| *x == tag) {
+                Some(idx) => mask |= 1 << idx,
+                None => return Err(format!("Unknown tag {}", tag))
+            }
+        }
+        Ok(TagFilter::new(mask))
+    }
+
+    /// The bit for `tag`, registering it if it hasn't been seen before.
+    fn tag_bit(&self, tag: &str) -> Result<u64, String> {
+        let tag = tag.trim().to_lowercase();
+        let mut names = self.tag_names.write().unwrap();
+        let idx = match names.iter().position(|x| *x == tag) {
+            Some(idx) => idx,
+            None if names.len() >= u64::BITS as usize =>
+                return Err(format!("Can't add tag {}: a wordlist can have at most 64 tags", tag)),
+            None => {
+                names.push(tag);
+                names.len() - 1
+            }
+        };
+        Ok(1 << idx)
+    }
+
+    /// The words in a file of one word per line, reporting how many lines couldn't be read.
+    fn read_word_list<R: BufRead>(&self, buf_reader: R) -> Vec<String> {
+        let mut failures = 0;
+        let words = readable_lines(buf_reader, &mut failures)
+            .map(|line| self.folding.normalize(&line))
+            .filter(|word| !word.is_empty())
+            .collect::<Vec<_>>();
+        (self.events)(&WordlistEvent::ListLoaded { words: words.len(), failures });
+        words
+    }
+
+    /// Applies `f` to the index and publishes the result. If searches are still using the
+    /// current index, `f` works on a copy of it and they carry on with the old one. The copy is
+    /// made without holding up `snapshot`, and only the first change after a snapshot pays for
+    /// it; later ones find the copy unshared and change it in place.
+    ///
+    /// Changes leave old copies of their paths behind; once those take up too much of the index
+    /// (see `ImmutableTrie::is_fragmented`), it's rebuilt.
+    fn update<T, F>(&self, f: F) -> T
+        where F: FnOnce(&mut ImmutableTrie) -> T {
+        let _writer = self.writer.lock().unwrap();
+        let result = self.change(f);
+        let index = self.snapshot();
+        if index.is_fragmented() {
+            self.build(Trie::from(&*index));
+        }
+        result
+    }
+
+    /// The part of `update` that applies `f`; the caller holds `writer`.
+    fn change<T, F>(&self, f: F) -> T
+        where F: FnOnce(&mut ImmutableTrie) -> T {
+        if let Some(index) = Arc::get_mut(&mut *self.index.write().unwrap()) {
+            return f(index);
         }
-        println!("Built tree in {}", start_build.elapsed().as_millis() as f64 / 1000.0);
+        // nothing else changes the index while `writer` is held, so the copy stays current
+        let mut index = ImmutableTrie::clone(&self.snapshot());
+        let result = f(&mut index);
+        *self.index.write().unwrap() = Arc::new(index);
+        result
+    }
+
+    /// Applies `f` to a mutable copy of the index and builds it, for changes to many words at
+    /// once; unlike `update`, this doesn't leave old copies of their paths behind.
+    fn rebuild<F: FnOnce(&mut Trie)>(&self, f: F) {
+        let _writer = self.writer.lock().unwrap();
+        let mut trie = Trie::from(&*self.snapshot());
+        f(&mut trie);
+        self.build(trie);
+    }
+
+    fn build(&self, trie: Trie) {
+        (self.events)(&WordlistEvent::Building);
+        let start_build = Instant::now();
+        let index = trie.build();
+        (self.events)(&WordlistEvent::Built { elapsed: start_build.elapsed(), memory: index.memory_usage() });
+        *self.index.write().unwrap() = Arc::new(index);
+    }
+
+    /// How much memory the current index takes up.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        self.snapshot().memory_usage()
+    }
+
+    /// `word` the way this wordlist indexes it.
+    pub fn normalize(&self, word: &str) -> String {
+        self.folding.normalize(word)
     }
 
-    pub fn contains(&'a self, word: &str) -> bool {
-        self.trie.contains(word)
+    pub fn contains(&self, word: &str) -> bool {
+        self.snapshot().contains(&self.folding.normalize(word))
     }
-    pub fn search(&'a self, regex: &str) -> Vec<String> {
-        self.trie.query_regex(regex)
+    /// Like `search_multithreaded`, running the search on the calling thread.
+    pub fn search(&self, regex: &str, config: &SearchConfig) -> Vec<SearchResult> {
+        self.query_with(&SingleThreaded, &self.regex_constraint(regex), config)
     }
 
-    pub fn search_multithreaded(&'a self, regex: &str, config: &SearchConfig) -> Vec<String> {
-        self.immut_trie.query_regex_results(regex, config)
+    pub fn search_multithreaded(&self, regex: &str, config: &SearchConfig) -> Vec<SearchResult> {
+        self.query(&self.regex_constraint(regex), config)
     }
-    pub fn search_callback<F>(&'a self, regex: &str, config: &SearchConfig, callback: F)
+    pub fn search_callback<F>(&self, regex: &str, config: &SearchConfig, callback: F) -> SearchSummary
         where F: ResultCallback {
-        self.immut_trie.query_regex_multithreaded(regex, config, callback)
+        self.query_callback(&self.regex_constraint(regex), config, callback)
+    }
+
+    /// Lazily yields the results of a regex search, which runs on a snapshot of the wordlist in
+    /// the background; see `SearchIter`.
+    pub fn search_iter(&self, regex: &str, config: &SearchConfig) -> SearchIter {
+        self.query_iter(self.regex_constraint(regex), config)
     }
 
-    pub fn anagram(&'a self, anagram: &str) -> Vec<String> {
-        self.trie.query_anagram(anagram)
+    /// Like `anagram_multithreaded`, running the search on the calling thread.
+    pub fn anagram(&self, anagram: &str, config: &SearchConfig) -> Vec<SearchResult> {
+        self.query_with(&SingleThreaded, &self.anagram_constraint(anagram), config)
     }
 
 
-    pub fn anagram_multithreaded(&'a self, regex: &str, config: &SearchConfig) -> Vec<String>{
-        self.immut_trie.query_anagram_results(regex, config)
+    pub fn anagram_multithreaded(&self, anagram: &str, config: &SearchConfig) -> Vec<SearchResult> {
+        self.query(&self.anagram_constraint(anagram), config)
     }
-    pub fn anagram_callback<F>(&'a self, anagram: &str, config: &SearchConfig, callback: F)
+    pub fn anagram_callback<F>(&self, anagram: &str, config: &SearchConfig, callback: F) -> SearchSummary
         where F: ResultCallback {
-        self.immut_trie.query_anagram_multithreaded(anagram, config, callback)
+        self.query_callback(&self.anagram_constraint(anagram), config, callback)
+    }
+
+    /// Like `search_iter`, for anagrams.
+    pub fn anagram_iter(&self, anagram: &str, config: &SearchConfig) -> SearchIter {
+        self.query_iter(self.anagram_constraint(anagram), config)
+    }
+
+    /// Starts a regex search on a snapshot of the wordlist that hands out results a page at a
+    /// time; see `PagedSearch`. `max_results` is ignored.
+    pub fn search_paged(&self, regex: &str, config: &SearchConfig) -> PagedSearch {
+        self.query_paged(self.regex_constraint(regex), config)
+    }
+
+    /// Like `search_paged`, for anagrams.
+    pub fn anagram_paged(&self, anagram: &str, config: &SearchConfig) -> PagedSearch {
+        self.query_paged(self.anagram_constraint(anagram), config)
+    }
+
+    /// A constraint matching `regex`, folded the way this wordlist folds words.
+    pub fn regex_constraint(&self, regex: &str) -> Regex {
+        Regex::new(&self.folding.fold(regex))
+    }
+
+    /// A constraint using up the letters of `anagram`, folded the way this wordlist folds words.
+    pub fn anagram_constraint(&self, anagram: &str) -> Anagram {
+        Anagram::new(&self.folding.fold(anagram))
+    }
+
+    /// Finds words (or phrases, if `config` allows spaces) satisfying `constraint`. Combine
+    /// constraints with `Constraint::and` and `Constraint::or` to check them all in one search.
+    pub fn query<C: Constraint>(&self, constraint: &C, config: &SearchConfig) -> Vec<SearchResult> {
+        self.query_with(&ThreadPool, constraint, config)
+    }
+
+    /// Like `query`, running the search on `executor`.
+    pub fn query_with<E: Executor, C: Constraint>(&self, executor: &E, constraint: &C,
+                                                  config: &SearchConfig) -> Vec<SearchResult> {
+        self.label(self.snapshot().query_results_with(executor, constraint, &self.configure(config)))
+    }
+
+    /// Like `query`, passing each result to `callback` until it returns `true`, and then saying
+    /// how the search went.
+    pub fn query_callback<C, F>(&self, constraint: &C, config: &SearchConfig, mut callback: F) -> SearchSummary
+        where C: Constraint, F: ResultCallback {
+        let name = self.name.clone();
+        let labelled = move |mut result: SearchResult, config: &SearchConfig| {
+            result.source_list = name.clone();
+            callback(result, config)
+        };
+        self.snapshot().query_multithreaded(constraint, &self.configure(config), labelled)
+    }
+
+    /// Why `word` (or a phrase, with spaces between its words) does or doesn't turn up in a
+    /// search for `query`; see `ImmutableTrie::explain`.
+    pub fn explain<C: Constraint>(&self, word: &str, query: &C, config: &SearchConfig) -> Explanation {
+        let phrase = word.split_whitespace().map(|word| self.folding.normalize(word)).collect::<Vec<_>>().join(" ");
+        self.snapshot().explain(&phrase, query, &self.configure(config))
+    }
+
+    /// Like `search_iter`, for any constraint.
+    pub fn query_iter<C: Constraint + 'static>(&self, constraint: C, config: &SearchConfig) -> SearchIter {
+        let snapshot = self.snapshot();
+        SearchIter::spawn(self.configure(config), self.name.clone(), move |config, callback|
+            snapshot.query_multithreaded(&constraint, config, callback))
+    }
+
+    /// Like `search_paged`, for any constraint.
+    pub fn query_paged<C: Constraint + 'static>(&self, constraint: C, config: &SearchConfig) -> PagedSearch {
+        let snapshot = self.snapshot();
+        let config = self.configure(config);
+        PagedSearch::spawn(self.name.clone(), move |requests, pages|
+            snapshot.query_paged(&constraint, &config, requests, pages))
+    }
+
+    /// Keeps `search` alive until `resume` is called with the returned token. A search that
+    /// isn't resumed within the park timeout (see `set_park_timeout`) is dropped, along with its
+    /// thread and snapshot of the index, the next time a search is parked or resumed.
+    pub fn park(&self, search: PagedSearch) -> ContinuationToken {
+        let token = ContinuationToken::random();
+        let mut parked = self.parked.lock().unwrap();
+        self.expire(&mut parked);
+        parked.insert(token.clone(), (Instant::now(), search));
+        token
+    }
+
+    /// Takes back a search parked with `park`; `None` if the token is unknown, was already used
+    /// or has expired.
+    pub fn resume(&self, token: &ContinuationToken) -> Option<PagedSearch> {
+        let mut parked = self.parked.lock().unwrap();
+        self.expire(&mut parked);
+        parked.remove(token).map(|(_, search)| search)
+    }
+
+    /// Drops the parked searches that have waited longer than the park timeout.
+    fn expire(&self, parked: &mut HashMap<ContinuationToken, (Instant, PagedSearch)>) {
+        parked.retain(|_, (parked_at, _)| parked_at.elapsed() < self.park_timeout);
+    }
+
+    /// Marks `results` as coming from this wordlist.
+    fn label(&self, mut results: Vec<SearchResult>) -> Vec<SearchResult> {
+        results.iter_mut().for_each(|result| result.source_list = self.name.clone());
+        results
+    }
+}
+
+impl Default for Wordlist {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, BufReader, Cursor, Read};
+    use crate::alphabet::Folding;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+    use crate::wordlist::trie::constraint::Constraint;
+    use crate::wordlist::trie::searchconfig::{CancellationToken, FrontierLimit, PageLimits, SearchConfig};
+    use crate::wordlist::trie::searchresult::SearchSummary;
+    use crate::wordlist::wordlist::{FileFormat, LoadProgress, Wordlist, WordlistEvent};
+
+    /// A wordlist of `words`, one per line.
+    fn wordlist(words: &str) -> Wordlist {
+        let wl = Wordlist::new();
+        wl.load_reader(Cursor::new(words.to_string()), FileFormat::builder().build());
+        wl
+    }
+
+    #[test]
+    fn load_from_reader() {
+        let wl = Wordlist::new();
+        wl.load_reader(Cursor::new("10 HELLO\n5 HELP\n\n7 GOODBYE\n"),
+                       FileFormat::builder().delimiter(' ').word_column(1).freq_column(0).build());
+
+        assert!(wl.contains("HELLO"));
+        assert!(wl.contains("goodbye"));
+        assert!(!wl.contains("GOOD"));
+    }
+
+    #[test]
+    fn bad_frequencies_are_failures() {
+        let wl = Wordlist::new();
+        let failures = Mutex::new(None);
+        wl.load_reader_with_progress(Cursor::new("word\tfreq\nHELLO\t10\nHELP\t-5\nHELM\tlots\nHERO\t3\n"),
+                                     FileFormat::builder().delimiter('\t').build(),
+                                     |event| if let LoadProgress::Done { failures: count, .. } = event {
+                                         *failures.lock().unwrap() = Some(count);
+                                     });
+
+        assert_eq!(*failures.lock().unwrap(), Some(3));
+        assert!(wl.contains("HELLO") && wl.contains("HERO"));
+        assert!(!wl.contains("WORD") && !wl.contains("HELP") && !wl.contains("HELM"));
+    }
+
+    #[test]
+    fn results_say_where_they_came_from() {
+        let mut wl = Wordlist::new();
+        wl.set_name("common");
+        wl.load_reader(Cursor::new("10 HELLO\n5 HELP\n"),
+                       FileFormat::builder().delimiter(' ').word_column(1).freq_column(0).build());
+
+        let result = wl.search_multithreaded("HELLO", &SearchConfig::new());
+        assert_eq!(result[0].words, vec!["HELLO"]);
+        assert_eq!(result[0].word_freqs, vec![10]);
+        assert_eq!(result[0].source_list.as_deref(), Some("common"));
+        assert_eq!(wl.search("HELP", &SearchConfig::new())[0].source_list.as_deref(), Some("common"));
+        assert_eq!(wl.anagram_iter("PLEH", &SearchConfig::new()).next().unwrap().word_freqs, vec![5]);
+    }
+
+    #[test]
+    fn load_reports_progress() {
+        let wl = wordlist("HELLO\nHELP\n");
+        let events = Mutex::new(vec![]);
+        wl.load_reader_with_progress(Cursor::new("5 GOOD\n3 BYE\nBAD\n\n4 HELM\n"),
+                                     FileFormat::builder().delimiter(' ').word_column(1).freq_column(0).build(),
+                                     |progress| events.lock().unwrap().push(progress));
+
+        let events = events.into_inner().unwrap();
+        assert_eq!(events[0], LoadProgress::Read { lines: 5 });
+        assert_eq!(events[1], LoadProgress::Parsed { lines: 5, total: Some(5) });
+        assert_eq!(events[2..5].iter().filter(|x| matches!(x, LoadProgress::Inserted { .. })).count(), 3);
+        assert!(matches!(events[5], LoadProgress::Done { words: 3, failures: 1, .. }));
+
+        let mut result = wl.search_multithreaded("(GOOD|BYE|HEL(M|P|LO))", &SearchConfig::new());
+        result.sort();
+        assert_eq!(result, vec!["BYE", "GOOD", "HELLO", "HELM", "HELP"]);
+    }
+
+    #[test]
+    fn read_errors_end_the_input() {
+        /// Fails every read, as reading a directory does.
+        struct Broken;
+
+        impl Read for Broken {
+            fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::Other, "broken"))
+            }
+        }
+
+        let wl = wordlist("HELLO\n");
+        let events = Mutex::new(vec![]);
+        wl.load_reader_with_progress(BufReader::new(Broken), FileFormat::builder().build(),
+                                     |progress| events.lock().unwrap().push(progress));
+
+        let events = events.into_inner().unwrap();
+        assert_eq!(events[0], LoadProgress::Read { lines: 0 });
+        assert!(matches!(events.last(), Some(LoadProgress::Done { words: 0, failures: 1, .. })));
+        assert!(wl.contains("HELLO"));
+    }
+
+    #[test]
+    fn events_go_to_handler() {
+        let events = Arc::new(Mutex::new(vec![]));
+        let mut wl = Wordlist::new();
+        let handler_events = events.clone();
+        wl.set_event_handler(move |event| handler_events.lock().unwrap().push(event.clone()));
+        wl.load_reader(Cursor::new("HELLO\nHELP\n"), FileFormat::builder().build());
+
+        let events = events.lock().unwrap();
+        assert_eq!(events[0], WordlistEvent::Load(LoadProgress::Read { lines: 2 }));
+        assert!(events.contains(&WordlistEvent::Building));
+        assert!(matches!(events.last(), Some(WordlistEvent::Built { memory, .. }) if memory.nodes == 6));
+    }
+
+    #[test]
+    fn extend_after_load() {
+        let wl = wordlist("HELLO\nHELP\n");
+        wl.extend(vec![("Good".to_string(), 3), ("bye".to_string(), 2)]);
+
+        assert!(wl.contains("HELLO"));
+        assert!(wl.contains("GOOD"));
+        let mut result = wl.search_multithreaded("(GOOD|HEL.)", &SearchConfig::new());
+        result.sort();
+        assert_eq!(result, vec!["GOOD", "HELP"]);
+    }
+
+    #[test]
+    fn add_and_remove_words() {
+        let wl = wordlist("HELLO\nHELP\n");
+        wl.add_word("helm", 1);
+        assert!(wl.remove_word("HELP"));
+        assert!(!wl.remove_word("GOOD"));
+        assert!(!wl.remove_word("HEL"));
+
+        let mut result = wl.search_multithreaded("HEL(LO|M|P)", &SearchConfig::new());
+        result.sort();
+        assert_eq!(result, vec!["HELLO", "HELM"]);
+        assert_eq!(wl.search("HEL(LO|M|P)", &SearchConfig::new()).len(), 2);
+    }
+
+    #[test]
+    fn updates_in_place_without_readers() {
+        let wl = wordlist("HELLO\nHELP\n");
+        let before = Arc::as_ptr(&wl.snapshot());
+        wl.add_word("HELM", 1);
+        assert!(std::ptr::eq(before, Arc::as_ptr(&wl.snapshot())));
+
+        // a search holding on to the index keeps the old one
+        let reader = wl.snapshot();
+        wl.add_word("HERO", 1);
+        assert!(!Arc::ptr_eq(&reader, &wl.snapshot()));
+        assert!(!reader.contains("HERO"));
+    }
+
+    #[test]
+    fn add_words_to_a_large_list() {
+        let wl = Wordlist::new();
+        let letters = "ABCDEFGHIJKLMNOPQR";
+        let words = letters.chars().flat_map(|a| letters.chars().flat_map(move |b| {
+            letters.chars().flat_map(move |c| letters.chars().map(move |d| format!("{}{}{}{}", a, b, c, d)))
+        }));
+        wl.extend(words.map(|word| (word, 1)));
+        let snapshot = wl.snapshot();
+        let prefixes = snapshot.memory_usage().prefixes;
+        assert!(prefixes > 100_000);
+
+        // AAAAZ needs one new prefix and ZZZ three, wherever they fall in the table
+        wl.add_word("AAAAZ", 5);
+        wl.add_word("ZZZ", 3);
+        wl.add_word("RRRR", 2);
+        assert_eq!(wl.memory_usage().prefixes, prefixes + 4);
+        assert!(!snapshot.contains("ZZZ"));
+
+        let mut config = SearchConfig::new();
+        config.max_results = Some(3);
+        config.deterministic = true;
+        let best = wl.search("(A|R|Z)*", &config);
+        assert_eq!(best, vec!["AAAAZ", "RRRR", "ZZZ"]);
+        assert_eq!(best.iter().map(|x| x.word_freqs[0]).collect::<Vec<_>>(), vec![5, 3, 3]);
+        assert_eq!(wl.search("ABC.", &config).len(), 3);
+    }
+
+    #[test]
+    fn many_updates_are_compacted() {
+        let wl = wordlist("HELLO\nHELP\n");
+        let letters = "ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+        let words = letters.chars()
+            .flat_map(|a| letters.chars().map(move |b| format!("HELLO{}{}", a, b)))
+            .collect::<Vec<_>>();
+        for word in &words {
+            wl.add_word(word, 1);
+        }
+
+        // without rebuilding, the copied paths alone would take 8 nodes a word
+        assert!(wl.memory_usage().nodes < 8 * words.len());
+        assert!(!wl.snapshot().is_fragmented());
+        assert!(words.iter().all(|word| wl.contains(word)));
+    }
+
+    #[test]
+    fn update_during_a_live_search() {
+        let wl = Wordlist::new();
+        let letters = "ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+        wl.extend(letters.chars().flat_map(|a| letters.chars().map(move |b| (format!("Q{}{}", a, b), 1))));
+        // more results than fit in the iterator's buffer, so the search waits for us to read them
+        let mut results = wl.search_iter("Q..", &SearchConfig::new());
+        let first = results.next().unwrap().text();
+
+        let before = wl.snapshot();
+        wl.add_word("HELLO", 1);
+        let copy = Arc::as_ptr(&wl.snapshot());
+        assert!(!std::ptr::eq(copy, Arc::as_ptr(&before)));
+        drop(before);
+        // the search still holds the old index, but the copy is only ours, so it isn't copied again
+        assert!(wl.ban_word("QAB"));
+        assert!(wl.remove_word("QCD"));
+        wl.add_word("HELP", 1);
+        assert!(std::ptr::eq(copy, Arc::as_ptr(&wl.snapshot())));
+
+        assert!(wl.contains("HELP") && !wl.contains("QCD"));
+        let seen = std::iter::once(first).chain(results.map(|result| result.text())).collect::<Vec<_>>();
+        assert_eq!(seen.len(), 26 * 26);
+        assert!(seen.iter().any(|word| word == "QCD"));
+        assert_eq!(wl.search_multithreaded("(QAB|QCD|HELP)", &SearchConfig::new()), vec!["HELP"]);
+    }
+
+    #[test]
+    fn dropping_a_paged_search_releases_the_index() {
+        let wl = wordlist("HELLO\nHELP\n");
+        let mut search = wl.search_paged("HEL(LO|P)", &SearchConfig::new());
+        assert_eq!(search.next_page(1).len(), 1);
+        drop(search);
+
+        let before = Arc::as_ptr(&wl.snapshot());
+        wl.add_word("HELM", 1);
+        assert!(std::ptr::eq(before, Arc::as_ptr(&wl.snapshot())));
+    }
+
+    #[test]
+    fn tag_filtered_search() {
+        let wl = Wordlist::new();
+        wl.load_reader(Cursor::new("BOSTON\t50\tplace,city\nBOXTOP\t90\n\
+                                    BOSTON\t0\tname\nDAYTON\t20\tplace,city\nLONDON\t80\tplace\n"),
+                       FileFormat::builder().delimiter('\t').tag_column(2).build());
+        wl.load_tags_reader(Cursor::new("boxtop\n"), "brand").unwrap();
+        assert!(wl.load_tag_file("/nonexistent/tags", "river").is_err());
+        assert_eq!(wl.tags(), vec!["place", "city", "name", "brand"]);
+
+        let mut config = SearchConfig::new();
+        config.tags = Some(wl.tag_filter(&["CITY"]).unwrap());
+        let mut result = wl.search_multithreaded("...TO.", &config);
+        result.sort();
+        assert_eq!(result, vec!["BOSTON", "DAYTON"]);
+
+        config.tags = Some(wl.tag_filter(&["place", "name"]).unwrap());
+        assert_eq!(wl.anagram_multithreaded("NOTSOB", &config), vec!["BOSTON"]);
+
+        config.tags = Some(wl.tag_filter(&["brand"]).unwrap());
+        assert_eq!(wl.search_multithreaded("...TO.", &config), vec!["BOXTOP"]);
+        assert!(wl.tag_filter(&["river"]).is_err());
+    }
+
+    #[test]
+    fn at_most_64_tags() {
+        let wl = Wordlist::new();
+        for i in 0..64 {
+            wl.load_tags_reader(Cursor::new("hello\n"), &format!("tag{}", i)).unwrap();
+        }
+        assert!(wl.load_tags_reader(Cursor::new("hello\n"), "one too many").is_err());
+        wl.load_tags_reader(Cursor::new("help\n"), "tag3").unwrap();
+
+        let progress = Mutex::new(None);
+        wl.load_reader_with_progress(Cursor::new("HELM\t1\ttag5,another\n"),
+                                     FileFormat::builder().delimiter('\t').tag_column(2).build(),
+                                     |event| if let LoadProgress::Done { failures, .. } = event {
+                                         *progress.lock().unwrap() = Some(failures);
+                                     });
+        assert_eq!(*progress.lock().unwrap(), Some(1));
+        assert_eq!(wl.tags().len(), 64);
+        let mut config = SearchConfig::new();
+        config.tags = Some(wl.tag_filter(&["tag5"]).unwrap());
+        assert_eq!(wl.search("HEL.", &config), vec!["HELM"]);
+    }
+
+    #[test]
+    fn exclusions_skip_results() {
+        let wl = Wordlist::new();
+        wl.load_exclusions_reader(Cursor::new("hello\n"));
+        assert!(wl.load_exclusions("/nonexistent/exclusions").is_err());
+        wl.load_reader(Cursor::new("HELLO\nHELP\nHELM\n"), FileFormat::builder().build());
+        assert!(wl.ban_word("HELM"));
+        assert!(!wl.ban_word("GOOD"));
+
+        assert_eq!(wl.search_multithreaded("HEL(LO|M|P)", &SearchConfig::new()), vec!["HELP"]);
+        assert_eq!(wl.search("HEL(LO|M|P)", &SearchConfig::new()), vec!["HELP"]);
+    }
+
+    #[test]
+    fn unreadable_lines_are_skipped() {
+        let events = Arc::new(Mutex::new(vec![]));
+        let mut wl = wordlist("HELLO\nHELP\nHELM\n");
+        let handler_events = events.clone();
+        wl.set_event_handler(move |event| handler_events.lock().unwrap().push(event.clone()));
+        wl.load_exclusions_reader(Cursor::new(b"hello\n\xff\xfe\nhelp\n".to_vec()));
+
+        assert!(events.lock().unwrap().contains(&WordlistEvent::ListLoaded { words: 2, failures: 1 }));
+        assert_eq!(wl.search("HEL(LO|M|P)", &SearchConfig::new()), vec!["HELM"]);
+    }
+
+    #[test]
+    fn exclusions_are_loaded_in_one_build() {
+        let words = "HELLO\nHELP\nHELM\nGOOD\nGOODBYE\n";
+        let excluded = "help\ngoodbye\n";
+        let before = Wordlist::new();
+        before.load_exclusions_reader(Cursor::new(excluded));
+        before.load_reader(Cursor::new(words), FileFormat::builder().build());
+        let after = wordlist(words);
+        after.load_exclusions_reader(Cursor::new(excluded));
+
+        assert_eq!(after.memory_usage().nodes, before.memory_usage().nodes);
+        assert_eq!(after.search("(HEL(LO|P|M)|GOOD(BYE)?)", &SearchConfig::new()).len(), 3);
+    }
+
+    #[test]
+    fn iterate_over_results() {
+        let wl = wordlist("HELLO\nHELP\nHELM\nGOOD\n");
+        let config = SearchConfig::new();
+
+        let mut result = wl.search_iter("HEL(LO|M|P)", &config).collect::<Vec<_>>();
+        result.sort();
+        assert_eq!(result, vec!["HELLO", "HELM", "HELP"]);
+        assert_eq!(wl.anagram_iter("ODGO", &config).collect::<Vec<_>>(), vec!["GOOD"]);
+
+        let mut first = wl.search_iter("HEL(LO|M|P)", &config);
+        assert!(first.next().unwrap().text().starts_with("HEL"));
+        drop(first);
+
+        let mut config = SearchConfig::new();
+        config.max_results = Some(2);
+        assert_eq!(wl.search_iter("HEL(LO|M|P)", &config).count(), 2);
+        let helm = wl.search_iter("HEL(LO|M|P)", &SearchConfig::new()).filter(|x| x.text().ends_with('M'));
+        assert_eq!(helm.collect::<Vec<_>>(), vec!["HELM"]);
+    }
+
+    #[test]
+    fn dropping_an_iterator_stops_its_search() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        /// Accepts everything, counting the letters it looks at.
+        struct Counting(Arc<AtomicUsize>);
+
+        impl Constraint for Counting {
+            type State = ();
+
+            fn start(&self) {}
+
+            fn step(&self, _state: &(), _letter: char) -> Option<()> {
+                self.0.fetch_add(1, Ordering::Relaxed);
+                Some(())
+            }
+
+            fn accept(&self, _state: &()) -> bool {
+                true
+            }
+        }
+
+        let wl = Wordlist::new();
+        let letters = "ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+        let words = letters.chars()
+            .flat_map(|a| letters.chars().flat_map(move |b| letters.chars().map(move |c| format!("{}{}{}", a, b, c))));
+        wl.extend(words.map(|word| (word, 1)));
+        let steps = Arc::new(AtomicUsize::new(0));
+        let mut results = wl.query_iter(Counting(steps.clone()), &SearchConfig::new());
+        // the search can't get more than a buffer's worth of results ahead, so it's still running
+        assert!(results.next().is_some());
+        drop(results);
+
+        // the search's thread has finished, and dropped its constraint on the way out
+        assert_eq!(Arc::strong_count(&steps), 1);
+        assert!(steps.load(Ordering::Relaxed) < 26 + 26 * 26 + 26 * 26 * 26);
+    }
+
+    #[test]
+    fn bans_use_the_wordlists_folding() {
+        let wl = Wordlist::with_folding(Folding::default().with_mapping('ñ', "NY"));
+        wl.load_reader(Cursor::new("año\n"), FileFormat::builder().build());
+
+        assert_eq!(wl.normalize("año"), "ANYO");
+        assert!(wl.ban_word("AÑO"));
+        assert!(wl.search("ANYO", &SearchConfig::new()).is_empty());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn searches_report_what_they_discarded() {
+        let wl = Wordlist::new();
+        let letters = "ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+        wl.extend(letters.chars().flat_map(|a| letters.chars().map(move |b| (format!("Q{}{}", a, b), 1))));
+
+        let mut config = SearchConfig::new();
+        let mut results = wl.search_iter("Q..", &config);
+        assert_eq!(results.by_ref().count(), 26 * 26);
+        assert_eq!(results.summary().map(|x| x.discarded), Some(0));
+
+        // the Q node alone has 26 children to squeeze into a frontier of 1
+        config.frontier_limit = Some(FrontierLimit::Total(1));
+        let mut results = wl.search_iter("Q..", &config);
+        let found = results.by_ref().collect::<Vec<_>>();
+        let summary = results.summary().unwrap();
+        assert!(summary.discarded > 0);
+        assert!(found.iter().all(|x| x.discarded <= summary.discarded));
+
+        let mut search = wl.search_paged("Q..", &config);
+        assert_eq!(search.summary(), SearchSummary::default());
+        search.next_page(26 * 26);
+        assert!(search.summary().discarded > 0);
+    }
+
+    #[test]
+    fn page_through_results() {
+        let wl = wordlist("HELLO\nHELP\nHELM\nHELD\nHELMS\nGOOD\n");
+
+        for deterministic in [false, true] {
+            let mut config = SearchConfig::new();
+            config.deterministic = deterministic;
+            let mut search = wl.search_paged("HEL(LO|M|P|D|MS)", &config);
+            let pages = [search.next_page(2), search.next_page(2), search.next_page(2), search.next_page(2)];
+            assert_eq!(pages.iter().map(Vec::len).collect::<Vec<_>>(), vec![2, 2, 1, 0]);
+            let mut all = pages.concat();
+            if deterministic {
+                assert_eq!(all, wl.search_iter("HEL(LO|M|P|D|MS)", &config).collect::<Vec<_>>());
+            }
+            all.sort();
+            assert_eq!(all, vec!["HELD", "HELLO", "HELM", "HELMS", "HELP"]);
+        }
+    }
+
+    #[test]
+    fn page_limits_apply_to_one_page() {
+        let wl = wordlist("HELLO\nHELP\nHELM\nHELD\nHELMS\nGOOD\n");
+
+        for deterministic in [false, true] {
+            // a cancelled page doesn't cut short the pages after it
+            let mut config = SearchConfig::new();
+            config.deterministic = deterministic;
+            let cancel = CancellationToken::new();
+            cancel.cancel();
+            let mut search = wl.search_paged("HEL(LO|M|P|D|MS)", &config);
+            assert!(search.next_page_with(5, PageLimits { cancel: Some(cancel), deadline: None }).is_empty());
+            assert_eq!(search.next_page(5).len(), 5);
+
+            // and every page gets the whole expansion budget
+            config.max_expansions = Some(3);
+            let mut search = wl.search_paged("HEL(LO|M|P|D|MS)", &config);
+            let mut all = vec![];
+            for _ in 0..20 {
+                all.extend(search.next_page(5));
+            }
+            all.sort();
+            assert_eq!(all, vec!["HELD", "HELLO", "HELM", "HELMS", "HELP"]);
+        }
+    }
+
+    #[test]
+    fn resume_parked_search() {
+        let wl = wordlist("HELLO\nHELP\nHELM\n");
+
+        let mut search = wl.anagram_paged("PLEH", &SearchConfig::new());
+        assert_eq!(search.next_page(1), vec!["HELP"]);
+        let token = wl.park(search);
+        let mut search = wl.resume(&token).unwrap();
+        assert!(wl.resume(&token).is_none());
+        assert!(search.next_page(1).is_empty());
+    }
+
+    #[test]
+    fn parked_searches_expire() {
+        let mut wl = Wordlist::new();
+        wl.set_park_timeout(Duration::ZERO);
+        wl.load_reader(Cursor::new("HELLO\nHELP\n"), FileFormat::builder().build());
+
+        let token = wl.park(wl.search_paged("HEL(LO|P)", &SearchConfig::new()));
+        assert!(wl.resume(&token).is_none());
+        // the search took its snapshot of the index with it
+        let before = Arc::as_ptr(&wl.snapshot());
+        wl.add_word("HELM", 1);
+        assert!(std::ptr::eq(before, Arc::as_ptr(&wl.snapshot())));
+    }
+
+    #[test]
+    fn bigrams_rank_phrases() {
+        let wl = Wordlist::new();
+        wl.load_reader(Cursor::new("100 TOO\n100 MANY\n1000 MAY\n1000 TOON\n"),
+                       FileFormat::builder().delimiter(' ').word_column(1).freq_column(0).build());
+        let mut config = SearchConfig::new();
+        config.space_penalty = Some(0);
+        config.spaces_allowed = 1;
+        config.deterministic = true;
+        config.max_results = Some(1);
+
+        assert_eq!(wl.anagram_multithreaded("TOOMANY", &config), vec!["MAY TOON"]);
+        assert!(wl.load_bigrams("/nonexistent/bigrams").is_err());
+        assert_eq!(wl.anagram_multithreaded("TOOMANY", &config), vec!["MAY TOON"]);
+        wl.load_bigrams_reader(Cursor::new("too many 500\nmany secrets 20\n"));
+        assert_eq!(wl.anagram_multithreaded("TOOMANY", &config), vec!["TOO MANY"]);
+        // a scorer picked in the config still wins
+        config.scorer = crate::wordlist::trie::scorer::by_name("weighted");
+        assert_eq!(wl.anagram_multithreaded("TOOMANY", &config), vec!["MAY TOON"]);
+    }
+
+    #[test]
+    fn query_with_constraints() {
+        let mut wl = wordlist("HELLO\nHELP\nHELM\n");
+        wl.set_name("test");
+
+        let constraint = wl.regex_constraint("hel(lo|m|p)").and(wl.anagram_constraint("pleh"));
+        let result = wl.query(&constraint, &SearchConfig::new());
+        assert_eq!(result, vec!["HELP"]);
+        assert_eq!(result[0].source_list.as_deref(), Some("test"));
+        assert_eq!(wl.query_iter(constraint, &SearchConfig::new()).collect::<Vec<_>>(), vec!["HELP"]);
+    }
+
+    #[test]
+    fn shared_between_threads() {
+        let wl = Arc::new(wordlist("HELLO\nHELP\n"));
+
+        let searchers = (0..4).map(|_| {
+            let wl = wl.clone();
+            thread::spawn(move || {
+                for _ in 0..20 {
+                    let result = wl.search_multithreaded("HEL(LO|P|M)", &SearchConfig::new());
+                    assert!(result.iter().any(|x| x == "HELLO"));
+                }
+            })
+        }).collect::<Vec<_>>();
+        for word in ["HELM", "HELD", "HELIX"] {
+            wl.add_word(word, 1);
+        }
+        searchers.into_iter().for_each(|x| x.join().unwrap());
+
+        let snapshot = wl.snapshot();
+        wl.remove_word("HELM");
+        assert!(snapshot.contains("HELM"));
+        assert!(!wl.contains("HELM"));
+    }
+}
@@ -0,0 +1,61 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// Runs the binary with `args`, piping `input` to it, and returns what it printed to stdout.
+fn run(args: &[&str], input: &str) -> String {
+    // run somewhere without a data/banned to pick up
+    let mut child = Command::new(env!("CARGO_BIN_EXE_puzzle-tools"))
+        .args(args)
+        .current_dir(std::env::temp_dir())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn piped_words_come_back() {
+    assert_eq!(run(&["--stdin", "--regex", "HEL."], "hello\nhelp\n"), "HELP\n");
+}
+
+#[test]
+fn piped_frequencies_are_pruned() {
+    let input = "5 HELLO\n500 HELP\n";
+    let args = ["--stdin", "--delimiter", " ", "--word-column", "1", "--freq-column", "0", "--regex", "HEL(LO|P)"];
+    assert_eq!(run(&args, input), "HELP\n");
+    let mut results = run(&[&args[..], &["--prune-freq", "0"]].concat(), input).lines()
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    results.sort();
+    assert_eq!(results, vec!["HELLO", "HELP"]);
+}
+
+#[test]
+fn piped_header_row_is_skipped() {
+    let args = ["--stdin", "--delimiter", "\t", "--regex", "HEL(LO|P)"];
+    assert_eq!(run(&args, "word\tfreq\nhello\t-5\nhelp\t500\n"), "HELP\n");
+}
+
+#[test]
+fn lists_loaded_before_the_words_still_apply() {
+    let dir = std::env::temp_dir().join(format!("puzzle-tools-cli-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = |name: &str, contents: &str| {
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_string()
+    };
+    let exclude = path("exclude", "hello\n");
+    let banned = path("banned", "helm\n");
+    let tags = format!("tool={}", path("tools", "help\nhelm\n"));
+
+    let args = ["--stdin", "--regex", "HEL(LO|P|M|D)", "--exclude", &exclude, "--banlist", &banned,
+        "--tag-file", &tags];
+    assert_eq!(run(&args, "hello\nhelp\nhelm\nheld\n").lines().count(), 2);
+    std::fs::remove_dir_all(&dir).unwrap();
+}